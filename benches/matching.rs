@@ -0,0 +1,90 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use image::{DynamicImage, Rgb, RgbImage};
+use mosaicify::{ColorSpace, MosaicBuilder, TileSource};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const TILES: usize = 10_000;
+const TILE_SIZE: u32 = 8;
+const GRID: u32 = 8;
+
+/// Tiles shaded between two random colors, so they differ in both color and layout.
+fn synthetic_tiles(rng: &mut StdRng) -> Vec<DynamicImage> {
+    (0..TILES)
+        .map(|_| {
+            let from: [u8; 3] = rng.gen();
+            let to: [u8; 3] = rng.gen();
+            let image = RgbImage::from_fn(TILE_SIZE, TILE_SIZE, |x, y| {
+                let t = (x + y) as f32 / (2 * TILE_SIZE - 2) as f32;
+                Rgb([0, 1, 2].map(|c| (from[c] as f32 + (to[c] as f32 - from[c] as f32) * t) as u8))
+            });
+            DynamicImage::ImageRgb8(image)
+        })
+        .collect()
+}
+
+fn matching(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let tiles = synthetic_tiles(&mut rng);
+    let side = TILE_SIZE * GRID;
+    let target = DynamicImage::ImageRgb8(RgbImage::from_fn(side, side, |_, _| Rgb(rng.gen())));
+
+    let mut group = c.benchmark_group("10k tiles");
+    group.sample_size(10);
+    for color_space in [ColorSpace::Rgb, ColorSpace::Lab, ColorSpace::Gray] {
+        group.bench_function(format!("{color_space:?}"), |b| {
+            b.iter_batched(
+                || tiles.clone(),
+                |tiles| {
+                    MosaicBuilder::new()
+                        .target_image(target.clone())
+                        .grid(GRID, GRID)
+                        .tiles(TileSource::Images(tiles))
+                        .color_space(color_space)
+                        .seed(0)
+                        .build()
+                        .expect("synthetic mosaic")
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// A realistic grid, where preparing the blocks of the target matters as
+/// much as comparing them with a modest library.
+fn large_grid(c: &mut Criterion) {
+    const GRID: u32 = 100;
+    const LIBRARY: usize = 500;
+    let mut rng = StdRng::seed_from_u64(1);
+    let tiles = synthetic_tiles(&mut rng)[..LIBRARY].to_vec();
+    let side = TILE_SIZE * GRID;
+    let target = DynamicImage::ImageRgb8(RgbImage::from_fn(side, side, |x, y| {
+        Rgb([(x * 255 / side) as u8, (y * 255 / side) as u8, rng.gen()])
+    }));
+
+    let mut group = c.benchmark_group("100x100 grid");
+    group.sample_size(10);
+    for color_space in [ColorSpace::Lab, ColorSpace::Gray] {
+        group.bench_function(format!("{color_space:?}"), |b| {
+            b.iter_batched(
+                || tiles.clone(),
+                |tiles| {
+                    MosaicBuilder::new()
+                        .target_image(target.clone())
+                        .grid(GRID, GRID)
+                        .tiles(TileSource::Images(tiles))
+                        .color_space(color_space)
+                        .seed(0)
+                        .build()
+                        .expect("synthetic mosaic")
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, matching, large_grid);
+criterion_main!(benches);
@@ -0,0 +1,204 @@
+use clap::{builder::PossibleValue, ValueEnum};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Assignment {
+    #[default]
+    Greedy,
+    Optimal,
+}
+
+impl ValueEnum for Assignment {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Assignment::Greedy, Assignment::Optimal]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Assignment::Greedy => PossibleValue::new("greedy")
+                .help("Fill blocks in random order, each taking the best tile still available."),
+            Assignment::Optimal => PossibleValue::new("optimal")
+                .help("Minimise the total difference over all blocks at once."),
+        })
+    }
+}
+
+impl std::fmt::Display for Assignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// The most steps of `hungarian` that are allowed, about half a minute of work.
+const MAX_STEPS: u128 = 10_000_000_000;
+
+/// The most costs that are compared at once, taking about 400 MB.
+const MAX_COSTS: u128 = 25_000_000;
+
+/// Whether `hungarian` can solve `rows` x `cols` costs at `capacity` without
+/// taking too long or too much memory.
+pub(crate) fn within_limits(rows: usize, cols: usize, capacity: usize) -> bool {
+    let steps = [rows, rows, cols, capacity]
+        .iter()
+        .try_fold(1u128, |steps, &n| steps.checked_mul(n as u128));
+    steps.is_some_and(|steps| steps <= MAX_STEPS) && rows as u128 * cols as u128 <= MAX_COSTS
+}
+
+/// Solves the linear assignment problem for a row-major `rows` x `cols` cost
+/// matrix with `rows <= cols`, where every column may be assigned to at most
+/// `capacity` rows. Returns the column assigned to each row.
+///
+/// This is the O(n²m) Hungarian algorithm; a column with capacity `k` is
+/// treated as `k` identical columns.
+pub(crate) fn hungarian(cost: &[f32], rows: usize, cols: usize, capacity: usize) -> Vec<usize> {
+    let slots = cols * capacity;
+    assert!(rows <= slots, "not enough columns to assign every row");
+    let cost = |row: usize, slot: usize| cost[row * cols + slot % cols] as f64;
+    // Index 0 of `u`, `v`, `way` and `row_of` is a sentinel; rows and slots are 1-based.
+    let mut u = vec![0.0; rows + 1];
+    let mut v = vec![0.0; slots + 1];
+    let mut row_of = vec![0; slots + 1];
+    let mut way = vec![0; slots + 1];
+    for row in 1..=rows {
+        row_of[0] = row;
+        let mut slot0 = 0;
+        let mut min = vec![f64::INFINITY; slots + 1];
+        let mut used = vec![false; slots + 1];
+        loop {
+            used[slot0] = true;
+            let row0 = row_of[slot0];
+            let mut delta = f64::INFINITY;
+            let mut slot1 = 0;
+            for slot in 1..=slots {
+                if used[slot] {
+                    continue;
+                }
+                let reduced = cost(row0 - 1, slot - 1) - u[row0] - v[slot];
+                if reduced < min[slot] {
+                    min[slot] = reduced;
+                    way[slot] = slot0;
+                }
+                if min[slot] < delta {
+                    delta = min[slot];
+                    slot1 = slot;
+                }
+            }
+            for slot in 0..=slots {
+                if used[slot] {
+                    u[row_of[slot]] += delta;
+                    v[slot] -= delta;
+                } else {
+                    min[slot] -= delta;
+                }
+            }
+            slot0 = slot1;
+            if row_of[slot0] == 0 {
+                break;
+            }
+        }
+        while slot0 != 0 {
+            let slot1 = way[slot0];
+            row_of[slot0] = row_of[slot1];
+            slot0 = slot1;
+        }
+    }
+    let mut assigned = vec![0; rows];
+    for slot in 1..=slots {
+        if row_of[slot] != 0 {
+            assigned[row_of[slot] - 1] = (slot - 1) % cols;
+        }
+    }
+    assigned
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn total(cost: &[f32], cols: usize, assigned: &[usize]) -> f32 {
+        assigned
+            .iter()
+            .enumerate()
+            .map(|(row, &col)| cost[row * cols + col])
+            .sum()
+    }
+
+    /// The lowest total cost over every assignment of rows to columns that
+    /// uses no column more than `capacity` times.
+    fn brute_force(cost: &[f32], rows: usize, cols: usize, capacity: usize) -> f32 {
+        fn go(cost: &[f32], row: usize, rows: usize, cols: usize, left: &mut [usize]) -> f32 {
+            if row == rows {
+                return 0.0;
+            }
+            let mut best = f32::INFINITY;
+            for col in 0..cols {
+                if left[col] > 0 {
+                    left[col] -= 1;
+                    best = best.min(cost[row * cols + col] + go(cost, row + 1, rows, cols, left));
+                    left[col] += 1;
+                }
+            }
+            best
+        }
+        go(cost, 0, rows, cols, &mut vec![capacity; cols])
+    }
+
+    fn check(rng: &mut StdRng, rows: usize, cols: usize, capacity: usize) {
+        let cost = (0..rows * cols)
+            .map(|_| rng.gen::<f32>())
+            .collect::<Vec<_>>();
+        let assigned = hungarian(&cost, rows, cols, capacity);
+        assert_eq!(assigned.len(), rows);
+        for col in 0..cols {
+            assert!(assigned.iter().filter(|&&c| c == col).count() <= capacity);
+        }
+        let expected = brute_force(&cost, rows, cols, capacity);
+        assert!((total(&cost, cols, &assigned) - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for rows in 1..=6 {
+            for cols in rows..=7 {
+                for _ in 0..5 {
+                    check(&mut rng, rows, cols, 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shares_columns_up_to_capacity() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for (rows, cols, capacity) in [(2, 1, 2), (5, 2, 3), (6, 3, 2), (7, 3, 3), (8, 4, 2)] {
+            for _ in 0..10 {
+                check(&mut rng, rows, cols, capacity);
+            }
+        }
+    }
+
+    #[test]
+    fn prefers_cheap_columns() {
+        #[rustfmt::skip]
+        let cost = [
+            4.0, 1.0, 3.0,
+            2.0, 0.0, 5.0,
+            3.0, 2.0, 2.0,
+        ];
+        assert_eq!(hungarian(&cost, 3, 3, 1), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn limits_grow_with_rows_squared() {
+        assert!(within_limits(1000, 1000, 1));
+        assert!(within_limits(2000, 500, 4));
+        assert!(!within_limits(3000, 3000, 1));
+        assert!(!within_limits(10, 10_000_000, 1));
+        assert!(!within_limits(usize::MAX, usize::MAX, usize::MAX));
+    }
+}
@@ -0,0 +1,290 @@
+use image::{
+    imageops::{crop_imm, flip_horizontal, resize, rotate180, rotate270, rotate90, FilterType},
+    Rgb32FImage,
+};
+
+use crate::tile_fit::{crop_size, TileFit};
+
+/// Side of the crops of `Augment::crops`, relative to the largest crop with
+/// the aspect ratio of the tile.
+const CROP_SCALE: f64 = 0.75;
+
+/// Extra tiles made from every source image by flipping, rotating and
+/// cropping it, to get more variety out of a small library. The variants of
+/// an image count as that image for `DuplicatePolicy::Avoid` and placement
+/// constraints.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Augment {
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    rotate: bool,
+    crops: u32,
+}
+
+impl Augment {
+    /// No variants, only the source images themselves.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the mirror image of every variant.
+    pub fn flip_horizontal(mut self, flip: bool) -> Self {
+        self.flip_horizontal = flip;
+        self
+    }
+
+    /// Adds every variant upside down.
+    pub fn flip_vertical(mut self, flip: bool) -> Self {
+        self.flip_vertical = flip;
+        self
+    }
+
+    /// Adds every variant rotated by 90, 180 and 270 degrees.
+    pub fn rotate(mut self, rotate: bool) -> Self {
+        self.rotate = rotate;
+        self
+    }
+
+    /// Adds `crops` crops of every image, spaced evenly from its top-left to
+    /// its bottom-right corner. Each is 3/4 the size of the largest crop with
+    /// the aspect ratio of the tile.
+    pub fn crops(mut self, crops: u32) -> Self {
+        self.crops = crops;
+        self
+    }
+
+    /// Every distinct variant, starting with the image left unchanged.
+    pub(crate) fn variants(&self) -> Vec<Variant> {
+        let generators = [
+            (self.flip_horizontal, Transform::MIRROR),
+            (self.flip_vertical, Transform::UPSIDE_DOWN),
+            (self.rotate, Transform::QUARTER_TURN),
+        ];
+        // Flips and rotations combine into at most the 8 symmetries of a square.
+        let mut transforms = vec![Transform::default()];
+        let mut i = 0;
+        while i < transforms.len() {
+            for &(_, generator) in generators.iter().filter(|(on, _)| *on) {
+                let transform = transforms[i].then(generator);
+                if !transforms.contains(&transform) {
+                    transforms.push(transform);
+                }
+            }
+            i += 1;
+        }
+        let crops = std::iter::once(None).chain((0..self.crops).map(|i| Some((i, self.crops))));
+        crops
+            .flat_map(|crop| {
+                transforms
+                    .iter()
+                    .map(move |&transform| Variant { crop, transform })
+            })
+            .collect()
+    }
+}
+
+/// A mirror image if `flip`, then rotated clockwise by `turns` quarter turns.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+struct Transform {
+    flip: bool,
+    turns: u8,
+}
+
+impl Transform {
+    const MIRROR: Transform = Transform {
+        flip: true,
+        turns: 0,
+    };
+    const UPSIDE_DOWN: Transform = Transform {
+        flip: true,
+        turns: 2,
+    };
+    const QUARTER_TURN: Transform = Transform {
+        flip: false,
+        turns: 1,
+    };
+
+    /// This transform followed by `next`. Rotating a mirror image turns it
+    /// the other way.
+    fn then(self, next: Transform) -> Transform {
+        let turns = if next.flip {
+            4 - self.turns
+        } else {
+            self.turns
+        };
+        Transform {
+            flip: self.flip != next.flip,
+            turns: (next.turns + turns) % 4,
+        }
+    }
+}
+
+/// One of the tiles made from a source image.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Variant {
+    /// Which of how many crops, or the whole image fitted by `TileFit`.
+    crop: Option<(u32, u32)>,
+    transform: Transform,
+}
+
+impl Variant {
+    pub(crate) fn crop(&self) -> Option<(u32, u32)> {
+        self.crop
+    }
+
+    /// Whether the fitted image is used as is, without flipping or rotating.
+    pub(crate) fn is_upright(&self) -> bool {
+        self.transform == Transform::default()
+    }
+
+    /// Size to fit the image to, so that it is `width` x `height` once
+    /// rotated.
+    pub(crate) fn upright_size(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.transform.turns % 2 == 1 {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Resizes the crop of `image`, or the whole of it with `tile_fit`, to
+    /// exactly `width` x `height`, before it is flipped and rotated.
+    pub(crate) fn fit(
+        &self,
+        image: &Rgb32FImage,
+        width: u32,
+        height: u32,
+        tile_fit: TileFit,
+    ) -> Rgb32FImage {
+        let Some((i, crops)) = self.crop else {
+            return tile_fit.apply(image, width, height);
+        };
+        let (image_width, image_height) = image.dimensions();
+        let (w, h) = crop_size(image_width, image_height, width, height);
+        let w = ((f64::from(w) * CROP_SCALE).round() as u32).max(1);
+        let h = ((f64::from(h) * CROP_SCALE).round() as u32).max(1);
+        let t = if crops > 1 {
+            f64::from(i) / f64::from(crops - 1)
+        } else {
+            0.5
+        };
+        let x = (f64::from(image_width - w) * t).round() as u32;
+        let y = (f64::from(image_height - h) * t).round() as u32;
+        resize(
+            &*crop_imm(image, x, y, w, h),
+            width,
+            height,
+            FilterType::Lanczos3,
+        )
+    }
+
+    /// Flips and rotates a fitted image.
+    pub(crate) fn transform(&self, image: &Rgb32FImage) -> Rgb32FImage {
+        let flipped;
+        let image = if self.transform.flip {
+            flipped = flip_horizontal(image);
+            &flipped
+        } else {
+            image
+        };
+        match self.transform.turns {
+            1 => rotate90(image),
+            2 => rotate180(image),
+            3 => rotate270(image),
+            _ => image.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    const IDENTITY: Transform = Transform {
+        flip: false,
+        turns: 0,
+    };
+
+    /// The pixels of the 2 x 2 image `[1, 2; 3, 4]` once transformed, in
+    /// reading order.
+    fn pixels(transform: Transform) -> Vec<u8> {
+        let image = Rgb32FImage::from_fn(2, 2, |x, y| Rgb([(1 + x + 2 * y) as f32, 0.0, 0.0]));
+        let variant = Variant {
+            crop: None,
+            transform,
+        };
+        variant
+            .transform(&image)
+            .pixels()
+            .map(|p| p.0[0] as u8)
+            .collect()
+    }
+
+    #[test]
+    fn transforms_move_pixels() {
+        let quarter = Transform::QUARTER_TURN;
+        #[rustfmt::skip]
+        let cases = [
+            (IDENTITY, [1, 2, 3, 4]),
+            (Transform::MIRROR, [2, 1, 4, 3]),
+            (Transform::UPSIDE_DOWN, [3, 4, 1, 2]),
+            (quarter, [3, 1, 4, 2]),
+            (quarter.then(quarter), [4, 3, 2, 1]),
+            (Transform::MIRROR.then(Transform::UPSIDE_DOWN), [4, 3, 2, 1]),
+            (Transform::MIRROR.then(quarter), [4, 2, 3, 1]),
+            (quarter.then(Transform::MIRROR), [1, 3, 2, 4]),
+            (quarter.then(quarter).then(quarter), [2, 4, 1, 3]),
+        ];
+        for (transform, expected) in cases {
+            assert_eq!(pixels(transform), expected, "{transform:?}");
+        }
+    }
+
+    #[test]
+    fn then_composes_the_transforms() {
+        let all = (0..4)
+            .flat_map(|turns| [false, true].map(|flip| Transform { flip, turns }))
+            .collect::<Vec<_>>();
+        for &a in &all {
+            for &b in &all {
+                let image = Rgb32FImage::from_fn(3, 2, |x, y| Rgb([x as f32, y as f32, 0.0]));
+                let variant = |transform| Variant {
+                    crop: None,
+                    transform,
+                };
+                let twice = variant(b).transform(&variant(a).transform(&image));
+                assert_eq!(variant(a.then(b)).transform(&image), twice, "{a:?}, {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn variants_are_distinct() {
+        let augment = Augment::new();
+        #[rustfmt::skip]
+        let cases = [
+            (augment, 1),
+            (augment.flip_horizontal(true), 2),
+            (augment.flip_vertical(true), 2),
+            // Both flips make a half turn.
+            (augment.flip_horizontal(true).flip_vertical(true), 4),
+            (augment.rotate(true), 4),
+            (augment.rotate(true).flip_horizontal(true), 8),
+            (augment.rotate(true).flip_horizontal(true).flip_vertical(true), 8),
+            (augment.flip_horizontal(true).crops(2), 6),
+        ];
+        for (augment, count) in cases {
+            let variants = augment.variants();
+            assert_eq!(variants.len(), count, "{augment:?}");
+            assert!(variants[0].is_upright() && variants[0].crop().is_none());
+            for (i, a) in variants.iter().enumerate() {
+                for b in &variants[i + 1..] {
+                    let same = a.crop == b.crop && pixels(a.transform) == pixels(b.transform);
+                    assert!(!same, "{augment:?}: {a:?} and {b:?}");
+                }
+            }
+        }
+    }
+}
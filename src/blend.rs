@@ -0,0 +1,184 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use image::{GrayImage, Luma, Pixel, Rgb, Rgb32FImage};
+
+use crate::lab::{lab2rgb, rgb2lab};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum BlendMode {
+    #[default]
+    Transfer,
+    Overlay,
+}
+
+impl ValueEnum for BlendMode {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[BlendMode::Transfer, BlendMode::Overlay]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            BlendMode::Transfer => PossibleValue::new("transfer")
+                .help("Match the mean and variance of the tile to the block in L*a*b* space."),
+            BlendMode::Overlay => PossibleValue::new("overlay")
+                .help("Draw the block over the tile with transparency."),
+        })
+    }
+}
+
+impl std::fmt::Display for BlendMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// Shifts the colours of `tile` toward `block` by `amount` between 0 and 1.
+/// Only the pixels inside `mask` are compared, so that what lies outside the
+/// shape of a cell does not pull the colours of the tile.
+pub(crate) fn blend(
+    tile: &Rgb32FImage,
+    block: &Rgb32FImage,
+    mask: Option<&GrayImage>,
+    amount: f32,
+    mode: BlendMode,
+) -> Rgb32FImage {
+    match mode {
+        BlendMode::Transfer => transfer(tile, block, mask, amount),
+        BlendMode::Overlay => {
+            let mut tile = tile.clone();
+            for (t, b) in tile.pixels_mut().zip(block.pixels()) {
+                t.apply2(b, |t, b| t + (b - t) * amount);
+            }
+            tile
+        }
+    }
+}
+
+fn to_lab(image: &Rgb32FImage) -> Vec<[f32; 3]> {
+    image.pixels().map(|Rgb(rgb)| rgb2lab(rgb)).collect()
+}
+
+/// The colours of `lab` at the pixels inside `mask`, or all of them.
+fn inside(lab: &[[f32; 3]], mask: Option<&GrayImage>) -> Vec<[f32; 3]> {
+    match mask {
+        Some(mask) => lab
+            .iter()
+            .zip(mask.pixels())
+            .filter(|(_, Luma([m]))| *m != 0)
+            .map(|(lab, _)| *lab)
+            .collect(),
+        None => lab.to_vec(),
+    }
+}
+
+fn mean_and_std(pixels: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let n = pixels.len().max(1) as f32;
+    let mut mean = [0.0; 3];
+    for p in pixels {
+        for c in 0..3 {
+            mean[c] += p[c] / n;
+        }
+    }
+    let mut var = [0.0; 3];
+    for p in pixels {
+        for c in 0..3 {
+            var[c] += (p[c] - mean[c]).powi(2) / n;
+        }
+    }
+    (mean, var.map(f32::sqrt))
+}
+
+/// Reinhard-style colour transfer: every L*a*b* channel of the tile is
+/// rescaled to the mean and standard deviation of the block, both measured
+/// inside `mask`.
+fn transfer(
+    tile: &Rgb32FImage,
+    block: &Rgb32FImage,
+    mask: Option<&GrayImage>,
+    amount: f32,
+) -> Rgb32FImage {
+    let tile_lab = to_lab(tile);
+    let (tile_mean, tile_std) = mean_and_std(&inside(&tile_lab, mask));
+    let (block_mean, block_std) = mean_and_std(&inside(&to_lab(block), mask));
+    let mut out = tile.clone();
+    for (p, lab) in out.pixels_mut().zip(tile_lab) {
+        let mut shifted = lab;
+        for c in 0..3 {
+            let scale = if tile_std[c] > f32::EPSILON {
+                block_std[c] / tile_std[c]
+            } else {
+                1.0
+            };
+            let target = (lab[c] - tile_mean[c]) * scale + block_mean[c];
+            shifted[c] = lab[c] + (target - lab[c]) * amount;
+        }
+        *p = Rgb(lab2rgb(&shifted).map(|c| c.clamp(0.0, 1.0)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A disc that leaves out the corners of a `size` x `size` image.
+    fn disc(size: u32) -> GrayImage {
+        let r = size as f32 / 2.0;
+        GrayImage::from_fn(size, size, |x, y| {
+            let inside = (x as f32 + 0.5 - r).hypot(y as f32 + 0.5 - r) <= r;
+            Luma([if inside { 255 } else { 0 }])
+        })
+    }
+
+    /// The same gradient inside the disc, and `corner` outside it.
+    fn masked(mask: &GrayImage, corner: [f32; 3]) -> Rgb32FImage {
+        Rgb32FImage::from_fn(mask.width(), mask.height(), |x, y| {
+            if mask.get_pixel(x, y).0[0] == 0 {
+                Rgb(corner)
+            } else {
+                Rgb([0.2 + x as f32 / 40.0, 0.4, 0.7 - y as f32 / 40.0])
+            }
+        })
+    }
+
+    #[test]
+    fn transfer_ignores_pixels_outside_the_mask() {
+        let mask = disc(16);
+        let tile = masked(&mask, [0.0, 0.0, 0.0]);
+        let block = masked(&mask, [0.5, 0.5, 0.5]);
+        let blended = blend(&tile, &block, Some(&mask), 1.0, BlendMode::Transfer);
+        for ((t, b), m) in tile.pixels().zip(blended.pixels()).zip(mask.pixels()) {
+            if m.0[0] != 0 {
+                for c in 0..3 {
+                    assert!((t[c] - b[c]).abs() < 1e-3, "{t:?} became {b:?}");
+                }
+            }
+        }
+
+        // Without the mask, the grey corners of the block pull the tile.
+        let unmasked = blend(&tile, &block, None, 1.0, BlendMode::Transfer);
+        let center = (8, 8);
+        let (t, u) = (
+            tile.get_pixel(center.0, center.1),
+            unmasked.get_pixel(center.0, center.1),
+        );
+        assert!(t.0.iter().zip(u.0).any(|(t, u)| (t - u).abs() > 0.01));
+    }
+
+    #[test]
+    fn blend_amount_zero_keeps_the_tile() {
+        let mask = disc(8);
+        let tile = masked(&mask, [0.0, 0.0, 0.0]);
+        let block = Rgb32FImage::from_pixel(8, 8, Rgb([1.0, 0.0, 0.0]));
+        for mode in [BlendMode::Transfer, BlendMode::Overlay] {
+            let blended = blend(&tile, &block, Some(&mask), 0.0, mode);
+            for (t, b) in tile.pixels().zip(blended.pixels()) {
+                for c in 0..3 {
+                    assert!((t[c] - b[c]).abs() < 1e-3, "{mode}: {t:?} became {b:?}");
+                }
+            }
+        }
+    }
+}
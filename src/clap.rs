@@ -1,12 +1,47 @@
-use clap::{arg, value_parser, Arg, ArgAction, ArgMatches, Command};
+use clap::{arg, builder::PossibleValue, value_parser, Arg, ArgAction, ArgMatches, Command};
 
-use crate::mosaic::ColorSpace;
+use mosaicify::{
+    Assignment, BlendMode, CellDistance, ColorSpace, Descriptor, Detail, DistanceMetric, Fit,
+    LayoutKind, Search, Structure, TileFit,
+};
 
 pub fn get_matches() -> ArgMatches {
     Command::new("mosaicify")
         .version("0.3.0")
         .author("Namacha411 <thdyk.4.11@gmail.com>")
         .about("Generates a mosaic image from a target image and a set of source images.")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("index")
+                .about("Precomputes the source images of a directory so later runs start faster.")
+                .arg(
+                    Arg::new("images")
+                        .help("Path to the directory containing source images, searched recursively")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    arg!(-s --tile_size <SIZE> "Tile size to index, as WIDTHxHEIGHT. May be repeated.")
+                        .long("tile-size")
+                        .value_parser(parse_size)
+                        .action(ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    arg!(-c --color_space [COLOR_SPACE] "Color space to index. May be repeated.")
+                        .value_parser(value_parser!(ColorSpace))
+                        .action(ArgAction::Append)
+                        .default_value("lab"),
+                )
+                .arg(
+                    arg!(--tile_fit [TILE_FIT] "How source images are fitted to the tiles to index. May be repeated.")
+                        .long("tile-fit")
+                        .value_parser(value_parser!(TileFit))
+                        .action(ArgAction::Append)
+                        .default_value("stretch"),
+                ),
+        )
         .arg(
             Arg::new("target")
                 .help("Path to the target image")
@@ -29,15 +64,183 @@ pub fn get_matches() -> ArgMatches {
         )
         .arg(
             Arg::new("images")
-                .help("Path to the directory containing source images")
+                .help("Paths to source images or directories containing them, searched recursively")
                 .required(true)
+                .num_args(1..)
                 .index(4),
         )
         .arg(
-            arg!(-c --color_space [COLOR_SPACE] "Color space to use for matching tiles. Options: 'rgb' for RGB space, 'lab' for Lab space, 'gray' for grayscale.")
+            arg!(-c --color_space [COLOR_SPACE] "Color space to use for matching tiles.")
                 .value_parser(value_parser!(ColorSpace))
                 .default_value("lab"),
         )
+        .arg(
+            arg!(-m --metric [METRIC] "Metric used to compare pixels of blocks and tiles.")
+                .value_parser(value_parser!(DistanceMetric))
+                .default_value("cie76"),
+        )
+        .arg(
+            arg!(--channel_weights [WEIGHTS] "Weights of the three channels of the color space, e.g. '1,0.5,0.5'.")
+                .long("channel-weights")
+                .value_parser(parse_weights)
+                .default_value("1,1,1"),
+        )
+        .arg(
+            arg!(--chroma_weight [WEIGHT] "Weight of Cb and Cr relative to Y' in the ycbcr color space.")
+                .long("chroma-weight")
+                .value_parser(value_parser!(f32))
+                .default_value("1"),
+        )
+        .arg(
+            arg!(--layout [LAYOUT] "How the target is divided into tiles.")
+                .value_parser(value_parser!(LayoutKind))
+                .default_value("grid"),
+        )
+        .arg(
+            arg!(--fit [FIT] "How the target is scaled to the size of the grid.")
+                .value_parser(value_parser!(Fit))
+                .default_value("stretch"),
+        )
+        .arg(
+            arg!(--tile_fit [TILE_FIT] "How source images are fitted to the aspect ratio of the tiles.")
+                .long("tile-fit")
+                .value_parser(value_parser!(TileFit))
+                .default_value("stretch"),
+        )
+        .arg(
+            arg!(--augment [KIND] "Also make tiles by flipping or rotating the source images. May be repeated.")
+                .value_parser([
+                    PossibleValue::new("hflip").help("Mirror the images left to right."),
+                    PossibleValue::new("vflip").help("Turn the images upside down."),
+                    PossibleValue::new("rotate").help("Rotate the images by 90, 180 and 270 degrees."),
+                ])
+                .value_delimiter(',')
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--crops [N] "Also make tiles from N crops of every source image.")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(--tile_size [SIZE] "Render the tiles at WIDTHxHEIGHT pixels from the source images.")
+                .long("tile-size")
+                .value_parser(parse_size),
+        )
+        .arg(
+            arg!(--output_width [PIXELS] "Render the mosaic this many pixels wide from the source images.")
+                .long("output-width")
+                .value_parser(value_parser!(u32).range(1..))
+                .conflicts_with("tile_size"),
+        )
+        .arg(
+            arg!(--max_memory [SIZE] "Decode no more source images at once than fit in this much memory, e.g. '2G'.")
+                .long("max-memory")
+                .value_parser(parse_memory),
+        )
+        .arg(
+            arg!(--detail [DETAIL] "How the detail of a region is measured for the quadtree layout.")
+                .value_parser(value_parser!(Detail))
+                .default_value("variance"),
+        )
+        .arg(
+            arg!(--detail_threshold [THRESHOLD] "Detail above which a region is split for the quadtree layout.")
+                .long("detail-threshold")
+                .value_parser(value_parser!(f32))
+                .default_value("0.1"),
+        )
+        .arg(
+            arg!(--min_tile_size [PIXELS] "Smallest side of a tile in the quadtree layout.")
+                .long("min-tile-size")
+                .value_parser(value_parser!(u32).range(1..))
+                .default_value("8"),
+        )
+        .arg(
+            arg!(--descriptor [DESCRIPTOR] "What is compared between blocks and tiles.")
+                .value_parser(value_parser!(Descriptor))
+                .default_value("grid"),
+        )
+        .arg(
+            arg!(--match_resolution [N] "Downsample blocks and tiles to at most NxN pixels before comparing them.")
+                .long("match-resolution")
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            arg!(--structure [STRUCTURE] "Also compare the internal structure of blocks and tiles.")
+                .value_parser(value_parser!(Structure)),
+        )
+        .arg(
+            arg!(--structure_weight [WEIGHT] "How much the structure counts against the color, between 0 and 1.")
+                .long("structure-weight")
+                .value_parser(parse_amount)
+                .default_value("0.5"),
+        )
+        .arg(
+            arg!(-s --search [SEARCH] "How candidate tiles are found for each block.")
+                .value_parser(value_parser!(Search))
+                .default_value("exact"),
+        )
+        .arg(
+            arg!(--candidates [N] "Number of tiles shortlisted by the approximate search.")
+                .value_parser(value_parser!(usize))
+                .default_value("16"),
+        )
+        .arg(
+            arg!(-a --assignment [ASSIGNMENT] "How tiles are assigned to blocks when avoiding duplicates.")
+                .value_parser(value_parser!(Assignment))
+                .default_value("greedy"),
+        )
+        .arg(
+            arg!(--min_distance [N] "Never place copies of the same tile within N cells of each other.")
+                .long("min-distance")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(--cell_distance [METRIC] "How the distance between cells is measured for --min-distance.")
+                .long("cell-distance")
+                .value_parser(value_parser!(CellDistance))
+                .default_value("chebyshev"),
+        )
+        .arg(
+            arg!(--max_uses [K] "Use each tile at most K times.")
+                .long("max-uses")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--blend [AMOUNT] "Shift each tile toward the colours of its block, from 0 to 1.")
+                .value_parser(parse_amount)
+                .default_value("0"),
+        )
+        .arg(
+            arg!(--blend_mode [MODE] "How tiles are shifted toward their blocks.")
+                .long("blend-mode")
+                .value_parser(value_parser!(BlendMode))
+                .default_value("transfer"),
+        )
+        .arg(
+            arg!(--seed [SEED] "Seed for random choices, to reproduce a previous mosaic.")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(-v --verbose "Print details such as the tile size and the seed used.")
+                .global(true),
+        )
+        .arg(
+            arg!(--include [PATTERN] "Only read source images whose path matches this glob. May be repeated.")
+                .value_parser(parse_pattern)
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            arg!(--exclude [PATTERN] "Skip source images whose path matches this glob. May be repeated.")
+                .value_parser(parse_pattern)
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            arg!(--follow_symlinks "Follow symbolic links when searching directories.")
+                .long("follow-symlinks")
+                .global(true),
+        )
         .arg(
             arg!(-o --output [OUTPUT] "output image file path")
                 .default_value("mosaic.jpg")
@@ -51,3 +254,62 @@ pub fn get_matches() -> ArgMatches {
         )
         .get_matches()
 }
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got '{s}'"))?;
+    let parse = |v: &str| match v.parse::<u32>() {
+        Ok(0) | Err(_) => Err(format!("invalid size '{s}'")),
+        Ok(v) => Ok(v),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_memory(s: &str) -> Result<usize, String> {
+    let upper = s.trim().to_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match upper[digits.len()..]
+        .trim_end_matches('B')
+        .trim_end_matches('I')
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("expected a size such as '512M' or '2G', got '{s}'")),
+    };
+    match digits.trim().parse::<usize>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(1 << shift)
+            .ok_or_else(|| format!("size too large: '{s}'")),
+        _ => Err(format!("expected a size such as '512M' or '2G', got '{s}'")),
+    }
+}
+
+fn parse_pattern(s: &str) -> Result<String, String> {
+    globset::Glob::new(s)
+        .map(|_| s.to_string())
+        .map_err(|e| format!("invalid pattern '{s}': {}", e.kind()))
+}
+
+fn parse_amount(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        _ => Err(format!("expected a number between 0 and 1, got '{s}'")),
+    }
+}
+
+fn parse_weights(s: &str) -> Result<[f32; 3], String> {
+    let weights = s
+        .split(',')
+        .map(|w| w.trim().parse::<f32>().ok().filter(|w| *w >= 0.0))
+        .collect::<Option<Vec<_>>>();
+    match weights.as_deref() {
+        Some(&[a, b, c]) => Ok([a, b, c]),
+        _ => Err(format!(
+            "expected three non-negative numbers separated by commas, got '{s}'"
+        )),
+    }
+}
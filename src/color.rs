@@ -0,0 +1,169 @@
+//! Conversions from sRGB (0..=1) to the color spaces offered for matching,
+//! besides L*a*b* which lives in `lab.rs`.
+
+fn linearize(c: f32) -> f32 {
+    if c > 0.04045 {
+        f32::powf((c + 0.055) / 1.055, 2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+/// https://bottosson.github.io/posts/oklab/
+pub(crate) fn rgb2oklab(rgb: &[f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(linearize).map(f64::from);
+    let l = 0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b;
+    let m = 0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b;
+    let s = 0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b;
+    let [l, m, s] = [l, m, s].map(f64::cbrt);
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+    .map(|c| c as f32)
+}
+
+/// Hue as a fraction of a full turn, shared by HSV and HSL.
+fn hue(rgb: &[f32; 3], max: f32, delta: f32) -> f32 {
+    let [r, g, b] = *rgb;
+    if delta == 0.0 {
+        return 0.0;
+    }
+    let h = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    h / 6.0
+}
+
+/// https://en.wikipedia.org/wiki/HSL_and_HSV
+/// Every channel is in 0..=1, hue included.
+pub(crate) fn rgb2hsv(rgb: &[f32; 3]) -> [f32; 3] {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    let min = rgb[0].min(rgb[1]).min(rgb[2]);
+    let delta = max - min;
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    [hue(rgb, max, delta), s, max]
+}
+
+/// https://en.wikipedia.org/wiki/HSL_and_HSV
+/// Every channel is in 0..=1, hue included.
+pub(crate) fn rgb2hsl(rgb: &[f32; 3]) -> [f32; 3] {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    let min = rgb[0].min(rgb[1]).min(rgb[2]);
+    let delta = max - min;
+    let l = (max + min) / 2.0;
+    let s = if l == 0.0 || l == 1.0 {
+        0.0
+    } else {
+        delta / (1.0 - (2.0 * l - 1.0).abs())
+    };
+    [hue(rgb, max, delta), s, l]
+}
+
+/// Full-range BT.601 Y'CbCr, as used by JPEG.
+pub(crate) fn rgb2ycbcr(rgb: &[f32; 3]) -> [f32; 3] {
+    let [r, g, b] = *rgb;
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        0.5 - 0.168736 * r - 0.331264 * g + 0.5 * b,
+        0.5 + 0.5 * r - 0.418688 * g - 0.081312 * b,
+    ]
+}
+
+/// https://en.wikipedia.org/wiki/CIELUV
+/// D65 white point, like `lab::rgb2lab`.
+pub(crate) fn rgb2luv(rgb: &[f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(linearize);
+    let x = r * 0.4124 + g * 0.3576 + b * 0.1805;
+    let y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+    let z = r * 0.0193 + g * 0.1192 + b * 0.9505;
+    let l = if y > 0.008856 {
+        116.0 * y.cbrt() - 16.0
+    } else {
+        903.3 * y
+    };
+    let denominator = x + 15.0 * y + 3.0 * z;
+    if denominator == 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+    let (un, vn) = (0.19784, 0.46834);
+    let u = 4.0 * x / denominator;
+    let v = 9.0 * y / denominator;
+    [l, 13.0 * l * (u - un), 13.0 * l * (v - vn)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metric::{DistanceMetric, Scorer},
+        mosaic::ColorSpace,
+    };
+
+    const WHITE: [f32; 3] = [1.0, 1.0, 1.0];
+    const RED: [f32; 3] = [1.0, 0.0, 0.0];
+    const GREEN: [f32; 3] = [0.0, 1.0, 0.0];
+    const BLUE: [f32; 3] = [0.0, 0.0, 1.0];
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3], tolerance: f32) {
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!(
+                (a - e).abs() <= tolerance,
+                "{actual:?} is not within {tolerance} of {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn oklab_matches_reference_values() {
+        assert_close(rgb2oklab(&WHITE), [1.0, 0.0, 0.0], 1e-3);
+        assert_close(rgb2oklab(&RED), [0.6280, 0.2249, 0.1258], 1e-3);
+        assert_close(rgb2oklab(&GREEN), [0.8664, -0.2339, 0.1795], 1e-3);
+        assert_close(rgb2oklab(&BLUE), [0.4520, -0.0325, -0.3115], 1e-3);
+    }
+
+    #[test]
+    fn luv_matches_reference_values() {
+        assert_close(rgb2luv(&WHITE), [100.0, 0.0, 0.0], 0.05);
+        assert_close(rgb2luv(&RED), [53.24, 175.01, 37.76], 0.1);
+        assert_close(rgb2luv(&GREEN), [87.73, -83.07, 107.40], 0.1);
+        assert_close(rgb2luv(&BLUE), [32.30, -9.40, -130.34], 0.1);
+        assert_eq!(rgb2luv(&[0.0; 3]), [0.0; 3]);
+    }
+
+    #[test]
+    fn hue_wraps_around_at_red() {
+        for convert in [rgb2hsv, rgb2hsl] {
+            assert_eq!(convert(&RED)[0], 0.0);
+            assert_close(convert(&GREEN), [1.0 / 3.0, 1.0, convert(&GREEN)[2]], 1e-6);
+            assert_close(convert(&BLUE), [2.0 / 3.0, 1.0, convert(&BLUE)[2]], 1e-6);
+            // Just past red towards yellow, and just short of it from magenta.
+            let yellowish = convert(&[1.0, 0.06, 0.0])[0];
+            let magentaish = convert(&[1.0, 0.0, 0.06])[0];
+            assert!((yellowish - 0.01).abs() < 1e-6, "{yellowish}");
+            assert!((magentaish - 0.99).abs() < 1e-6, "{magentaish}");
+            assert_eq!(convert(&[0.5; 3])[..2], [0.0, 0.0]);
+        }
+        assert_eq!(rgb2hsv(&RED)[1..], [1.0, 1.0]);
+        assert_eq!(rgb2hsl(&RED)[1..], [1.0, 0.5]);
+    }
+
+    #[test]
+    fn scorer_measures_hue_around_the_circle() {
+        for color_space in [ColorSpace::Hsv, ColorSpace::Hsl] {
+            let scorer = Scorer::new(DistanceMetric::Cie76, color_space, [1.0; 3], 1.0);
+            let d = scorer.pixel(&[0.95, 0.5, 0.5], &[0.05, 0.5, 0.5]);
+            assert!((d - 0.1).abs() < 1e-6, "{d}");
+            let d = scorer.pixel(&[0.3, 0.5, 0.5], &[0.6, 0.9, 0.5]);
+            assert!((d - 0.5).abs() < 1e-6, "{d}");
+        }
+        let rgb = Scorer::new(DistanceMetric::Cie76, ColorSpace::Rgb, [1.0; 3], 1.0);
+        let d = rgb.pixel(&[0.95, 0.5, 0.5], &[0.05, 0.5, 0.5]);
+        assert!((d - 0.9).abs() < 1e-6, "{d}");
+    }
+}
@@ -0,0 +1,109 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use image::{
+    imageops::{resize, FilterType::Triangle},
+    Rgb, Rgb32FImage,
+};
+use itertools::iproduct;
+
+use crate::mosaic::{ColorSpace, Tile};
+
+/// Number of bin centres along each RGB axis of a colour histogram.
+const HISTOGRAM_BINS: usize = 5;
+
+/// What the matcher compares between a block and a tile.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Descriptor {
+    /// Every pixel, or an N×N grid with `MosaicBuilder::match_resolution`.
+    #[default]
+    Grid,
+    /// The mean colour.
+    Mean,
+    /// The distribution of colours, regardless of where they are.
+    Histogram,
+}
+
+impl ValueEnum for Descriptor {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Descriptor::Grid, Descriptor::Mean, Descriptor::Histogram]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Descriptor::Grid => PossibleValue::new("grid")
+                .help("Compare pixels, downsampled to --match-resolution if given."),
+            Descriptor::Mean => PossibleValue::new("mean").help("Compare the mean colors."),
+            Descriptor::Histogram => {
+                PossibleValue::new("histogram").help("Compare the color histograms.")
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl Descriptor {
+    /// Replaces the full-resolution features of `tile` with this descriptor.
+    /// `resolution` is the side of the grid the tile is downsampled to.
+    pub(crate) fn apply(self, tile: &mut Tile, resolution: Option<u32>, color_space: ColorSpace) {
+        let image = &tile.image;
+        match self {
+            Descriptor::Mean => {
+                tile.features = color_space.features(&Rgb32FImage::from_pixel(1, 1, mean(image)));
+                return;
+            }
+            Descriptor::Histogram => tile.histogram = Some(histogram(image)),
+            Descriptor::Grid => {}
+        }
+        if let Some(resolution) = resolution {
+            let width = resolution.min(image.width());
+            let height = resolution.min(image.height());
+            if (width, height) != image.dimensions() {
+                tile.features = color_space.features(&resize(image, width, height, Triangle));
+            }
+        }
+    }
+}
+
+fn mean(image: &Rgb32FImage) -> Rgb<f32> {
+    let n = image.pixels().len().max(1) as f32;
+    let mut sum = [0.0; 3];
+    for Rgb(rgb) in image.pixels() {
+        for (s, c) in sum.iter_mut().zip(rgb) {
+            *s += c;
+        }
+    }
+    Rgb(sum.map(|s| s / n))
+}
+
+/// RGB histogram with `HISTOGRAM_BINS` evenly spaced centres per channel,
+/// each pixel spread over its neighbouring centres so that slightly
+/// different colours still overlap. Sums to 1.
+pub(crate) fn histogram(image: &Rgb32FImage) -> Vec<f32> {
+    let mut histogram = vec![0.0; HISTOGRAM_BINS.pow(3)];
+    let n = image.pixels().len().max(1) as f32;
+    let last = (HISTOGRAM_BINS - 1) as f32;
+    for Rgb(rgb) in image.pixels() {
+        let [r, g, b] = rgb.map(|c| {
+            let position = c.clamp(0.0, 1.0) * last;
+            let low = (position.floor() as usize).min(HISTOGRAM_BINS - 2);
+            let high_weight = position - low as f32;
+            [(low, 1.0 - high_weight), (low + 1, high_weight)]
+        });
+        for ((ri, rw), (gi, gw), (bi, bw)) in iproduct!(r, g, b) {
+            histogram[(ri * HISTOGRAM_BINS + gi) * HISTOGRAM_BINS + bi] += rw * gw * bw / n;
+        }
+    }
+    histogram
+}
+
+/// Half the L1 distance between two histograms, between 0 and 1.
+pub(crate) fn histogram_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f32>() / 2.0
+}
@@ -0,0 +1,173 @@
+use std::{fmt, io, path::PathBuf};
+
+use image::ImageError;
+
+use crate::{metric::DistanceMetric, mosaic::ColorSpace};
+
+#[derive(Debug)]
+pub enum MosaicError {
+    MissingTarget,
+    MissingTiles,
+    InvalidGrid,
+    IncompatibleMetric {
+        metric: DistanceMetric,
+        color_space: ColorSpace,
+    },
+    ConstraintsWithOptimal,
+    OptimalWithDuplicates,
+    AssignmentTooLarge {
+        cells: usize,
+        tiles: usize,
+    },
+    GridLargerThanTarget {
+        row_size: u32,
+        col_size: u32,
+        width: u32,
+        height: u32,
+    },
+    CellTooSmall {
+        width: u32,
+        height: u32,
+    },
+    TargetRead {
+        path: PathBuf,
+        source: io::Error,
+    },
+    TargetDecode {
+        path: PathBuf,
+        source: ImageError,
+    },
+    TileRead {
+        path: PathBuf,
+        source: io::Error,
+    },
+    TileDecode {
+        path: PathBuf,
+        source: ImageError,
+    },
+    InvalidPattern {
+        pattern: String,
+        source: globset::Error,
+    },
+    EmptyTileSet,
+    NoMatch,
+    ConstraintUnsatisfied {
+        cell: (u32, u32),
+        constraint: String,
+    },
+    Save {
+        path: PathBuf,
+        source: ImageError,
+    },
+    IndexWrite {
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+impl fmt::Display for MosaicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MosaicError::MissingTarget => write!(f, "No target image was given."),
+            MosaicError::MissingTiles => write!(f, "No tile source was given."),
+            MosaicError::InvalidGrid => write!(f, "The grid size must be at least 1x1."),
+            MosaicError::IncompatibleMetric {
+                metric,
+                color_space,
+            } => write!(
+                f,
+                "The {metric} metric cannot be used with the {color_space} color space."
+            ),
+            MosaicError::ConstraintsWithOptimal => write!(
+                f,
+                "Placement constraints cannot be used with the optimal assignment."
+            ),
+            MosaicError::OptimalWithDuplicates => write!(
+                f,
+                "The optimal assignment can only be used when duplicates are avoided."
+            ),
+            MosaicError::AssignmentTooLarge { cells, tiles } => write!(
+                f,
+                "Assigning {tiles} source images to {cells} tiles is too large for the optimal assignment; use the greedy one or fewer tiles."
+            ),
+            MosaicError::GridLargerThanTarget {
+                row_size,
+                col_size,
+                width,
+                height,
+            } => write!(
+                f,
+                "A {row_size}x{col_size} grid does not fit in the {width}x{height} target image."
+            ),
+            MosaicError::CellTooSmall { width, height } => write!(
+                f,
+                "Cells of {width}x{height} pixels are too small for the layout."
+            ),
+            MosaicError::TargetRead { path, source } => {
+                write!(
+                    f,
+                    "Failed to open the target image {}: {source}",
+                    path.display()
+                )
+            }
+            MosaicError::TargetDecode { path, source } => {
+                write!(
+                    f,
+                    "Failed to decode the target image {}: {source}",
+                    path.display()
+                )
+            }
+            MosaicError::TileRead { path, source } => {
+                write!(
+                    f,
+                    "Failed to read the source image {}: {source}",
+                    path.display()
+                )
+            }
+            MosaicError::TileDecode { path, source } => {
+                write!(
+                    f,
+                    "Failed to decode the source image {}: {source}",
+                    path.display()
+                )
+            }
+            MosaicError::InvalidPattern { pattern, source } => {
+                write!(f, "Invalid file pattern '{pattern}': {source}")
+            }
+            MosaicError::EmptyTileSet => write!(f, "No source images were found."),
+            MosaicError::NoMatch => write!(f, "Failed to find the best matching image."),
+            MosaicError::ConstraintUnsatisfied {
+                cell: (x, y),
+                constraint,
+            } => write!(
+                f,
+                "No tile can be placed in cell ({x}, {y}) without breaking {constraint}."
+            ),
+            MosaicError::Save { path, source } => {
+                write!(
+                    f,
+                    "Failed to save the mosaic image {}: {source}",
+                    path.display()
+                )
+            }
+            MosaicError::IndexWrite { path, source } => {
+                write!(f, "Failed to write the index {}: {source}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for MosaicError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MosaicError::TargetRead { source, .. }
+            | MosaicError::TileRead { source, .. }
+            | MosaicError::IndexWrite { source, .. } => Some(source),
+            MosaicError::TargetDecode { source, .. }
+            | MosaicError::TileDecode { source, .. }
+            | MosaicError::Save { source, .. } => Some(source),
+            MosaicError::InvalidPattern { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use image::{Rgb, Rgb32FImage};
+use rayon::prelude::*;
+
+/// Per-pixel values of an image in some color space, stored contiguously in
+/// row-major order with `channels` values per pixel.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Features {
+    width: usize,
+    height: usize,
+    channels: usize,
+    data: Vec<f32>,
+}
+
+impl Features {
+    /// Returns `None` if `data` does not hold `width * height * channels` values.
+    pub(crate) fn from_raw(
+        width: usize,
+        height: usize,
+        channels: usize,
+        data: Vec<f32>,
+    ) -> Option<Self> {
+        (data.len() == width * height * channels).then_some(Self {
+            width,
+            height,
+            channels,
+            data,
+        })
+    }
+
+    /// Converts every pixel of `image` to `N` channels.
+    pub(crate) fn from_image<const N: usize>(
+        image: &Rgb32FImage,
+        convert: impl Fn(&Rgb<f32>) -> [f32; N] + Send + Sync,
+    ) -> Self {
+        // Only large images such as the target are worth splitting.
+        let data = image
+            .par_pixels()
+            .with_min_len(1 << 14)
+            .map(convert)
+            .collect::<Vec<_>>()
+            .into_flattened();
+        Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            channels: N,
+            data,
+        }
+    }
+
+    pub(crate) fn width(&self) -> usize {
+        self.width
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.height
+    }
+
+    pub(crate) fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub(crate) fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub(crate) fn pixel(&self, x: usize, y: usize) -> &[f32] {
+        let start = (y * self.width + x) * self.channels;
+        &self.data[start..start + self.channels]
+    }
+
+    /// Copies the `width` x `height` region whose top-left pixel is (x, y).
+    pub(crate) fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        let row = width * self.channels;
+        let mut data = Vec::with_capacity(row * height);
+        for y in y..y + height {
+            let start = (y * self.width + x) * self.channels;
+            data.extend_from_slice(&self.data[start..start + row]);
+        }
+        Self {
+            width,
+            height,
+            channels: self.channels,
+            data,
+        }
+    }
+
+    /// Whether both have the same size and number of channels.
+    pub(crate) fn is_comparable(&self, other: &Self) -> bool {
+        (self.width, self.height, self.channels) == (other.width, other.height, other.channels)
+    }
+}
@@ -0,0 +1,572 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Take, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use image::{DynamicImage, RgbImage};
+use rayon::prelude::*;
+
+use crate::{
+    error::MosaicError,
+    features::Features,
+    mosaic::{prepare_tile, skip, ColorSpace, Tile},
+    progress::{Progress, Stage},
+    scan::Scan,
+    source::decode_tile,
+    tile_fit::TileFit,
+};
+
+pub(crate) const INDEX_FILE: &str = ".mosaicify-index";
+const MAGIC: &[u8; 8] = b"MOSAICIX";
+const VERSION: u32 = 5;
+
+/// What `update_index` changed in the index of a directory.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct IndexSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Files that could not be read or are not decodable images.
+    pub skipped: usize,
+}
+
+/// Identifies the version of a source file that an index entry was computed from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    secs: u64,
+    nanos: u32,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            len: metadata.len(),
+            secs: modified.as_secs(),
+            nanos: modified.subsec_nanos(),
+        })
+    }
+}
+
+struct CachedTile {
+    color_space: String,
+    tile_fit: String,
+    thumbnail: RgbImage,
+    features: Features,
+}
+
+struct Entry {
+    stamp: Stamp,
+    tiles: Vec<CachedTile>,
+}
+
+fn find_tile(
+    tiles: &[CachedTile],
+    color_space: ColorSpace,
+    tile_fit: TileFit,
+    width: u32,
+    height: u32,
+) -> Option<&CachedTile> {
+    let (color_space, tile_fit) = (color_space.to_string(), tile_fit.to_string());
+    tiles.iter().find(|t| {
+        t.color_space == color_space
+            && t.tile_fit == tile_fit
+            && t.thumbnail.dimensions() == (width, height)
+    })
+}
+
+/// Precomputed thumbnails and features of the source images under one
+/// directory, keyed by relative path and invalidated by file size and
+/// modification time.
+#[derive(Default)]
+pub(crate) struct TileIndex {
+    directory: PathBuf,
+    entries: BTreeMap<String, Entry>,
+}
+
+/// The key of `path` in the index of `directory`, with `/` separators.
+fn key(directory: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(directory).ok()?;
+    let parts = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(parts.join("/"))
+}
+
+impl TileIndex {
+    fn path(directory: &Path) -> PathBuf {
+        directory.join(INDEX_FILE)
+    }
+
+    /// A missing or unreadable index is treated as empty, since it is only a cache.
+    pub(crate) fn load(directory: &Path) -> Self {
+        let entries = File::open(Self::path(directory))
+            .and_then(|file| {
+                let len = file.metadata()?.len();
+                Self::read(&mut BufReader::new(file).take(len))
+            })
+            .unwrap_or_default();
+        Self {
+            directory: directory.to_path_buf(),
+            entries,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the cached tile for `path` if the file has not changed since it was indexed.
+    pub(crate) fn lookup(
+        &self,
+        path: &Path,
+        color_space: ColorSpace,
+        tile_fit: TileFit,
+        width: u32,
+        height: u32,
+    ) -> Option<Tile> {
+        let entry = self.entries.get(&key(&self.directory, path)?)?;
+        if entry.stamp != Stamp::of(path).ok()? {
+            return None;
+        }
+        let cached = find_tile(&entry.tiles, color_space, tile_fit, width, height)?;
+        Some(Tile {
+            image: DynamicImage::ImageRgb8(cached.thumbnail.clone()).into_rgb32f(),
+            features: cached.features.clone(),
+            shape: None,
+            histogram: None,
+        })
+    }
+
+    /// Reads an index of `r.limit()` bytes. Sizes are checked against the
+    /// bytes left before anything is allocated for them, so a corrupted index
+    /// fails to read instead of exhausting memory.
+    fn read(r: &mut Take<impl Read>) -> io::Result<BTreeMap<String, Entry>> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(r)? != VERSION {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut entries = BTreeMap::new();
+        for _ in 0..read_u64(r)? {
+            let name = read_string(r)?;
+            let stamp = Stamp {
+                len: read_u64(r)?,
+                secs: read_u64(r)?,
+                nanos: read_u32(r)?,
+            };
+            let mut tiles = vec![];
+            for _ in 0..read_u32(r)? {
+                let color_space = read_string(r)?;
+                let tile_fit = read_string(r)?;
+                let width = read_u32(r)?;
+                let height = read_u32(r)?;
+                let (w, h) = (u64::from(width), u64::from(height));
+                let mut pixels = vec![0; checked_len(r, &[w, h, 3], 1)?];
+                r.read_exact(&mut pixels)?;
+                let thumbnail =
+                    RgbImage::from_raw(width, height, pixels).ok_or(io::ErrorKind::InvalidData)?;
+                let channels = read_u32(r)?;
+                let len = checked_len(r, &[w, h, u64::from(channels)], 4)?;
+                let data = (0..len)
+                    .map(|_| read_f32(r))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let channels = channels as usize;
+                let features = Features::from_raw(width as usize, height as usize, channels, data)
+                    .ok_or(io::ErrorKind::InvalidData)?;
+                tiles.push(CachedTile {
+                    color_space,
+                    tile_fit,
+                    thumbnail,
+                    features,
+                });
+            }
+            entries.insert(name, Entry { stamp, tiles });
+        }
+        Ok(entries)
+    }
+
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (name, entry) in &self.entries {
+            write_string(w, name)?;
+            w.write_all(&entry.stamp.len.to_le_bytes())?;
+            w.write_all(&entry.stamp.secs.to_le_bytes())?;
+            w.write_all(&entry.stamp.nanos.to_le_bytes())?;
+            w.write_all(&(entry.tiles.len() as u32).to_le_bytes())?;
+            for tile in &entry.tiles {
+                write_string(w, &tile.color_space)?;
+                write_string(w, &tile.tile_fit)?;
+                w.write_all(&tile.thumbnail.width().to_le_bytes())?;
+                w.write_all(&tile.thumbnail.height().to_le_bytes())?;
+                w.write_all(tile.thumbnail.as_raw())?;
+                w.write_all(&(tile.features.channels() as u32).to_le_bytes())?;
+                for value in tile.features.as_slice() {
+                    w.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        w.flush()
+    }
+
+    fn save(&self, directory: &Path) -> Result<(), MosaicError> {
+        let path = Self::path(directory);
+        File::create(&path)
+            .and_then(|file| self.write(&mut BufWriter::new(file)))
+            .map_err(|source| MosaicError::IndexWrite { path, source })
+    }
+}
+
+/// Brings the index of `directory` up to date for every combination of
+/// `color_spaces`, `tile_fits` and tile `sizes`, decoding only files that are new, changed
+/// or missing one of the requested combinations. Files are found with `scan`
+/// and those that could not be read or are not decodable images are left
+/// out.
+pub fn update_index(
+    directory: &Path,
+    scan: &Scan,
+    color_spaces: &[ColorSpace],
+    tile_fits: &[TileFit],
+    sizes: &[(u32, u32)],
+    progress: &dyn Progress,
+) -> Result<IndexSummary, MosaicError> {
+    let mut index = TileIndex::load(directory);
+    let mut summary = IndexSummary::default();
+    let paths = scan.files(directory, &scan.filter()?)?;
+    let mut jobs = vec![];
+    for path in paths {
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => {
+                skip(e, progress)?;
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let Some(name) = key(directory, &path) else {
+            continue;
+        };
+        let old = index.entries.remove(&name);
+        let stamp = match Stamp::of(&path) {
+            Ok(stamp) => stamp,
+            Err(source) => {
+                skip(MosaicError::TileRead { path, source }, progress)?;
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        match &old {
+            None => summary.added += 1,
+            Some(entry) if entry.stamp != stamp => summary.updated += 1,
+            Some(entry)
+                if iter_combinations(color_spaces, tile_fits, sizes)
+                    .all(|(c, f, (w, h))| find_tile(&entry.tiles, c, f, w, h).is_some()) =>
+            {
+                summary.unchanged += 1
+            }
+            Some(_) => summary.updated += 1,
+        }
+        jobs.push((path, name, stamp, old));
+    }
+    summary.removed = index.entries.len();
+
+    progress.start(Stage::Index, jobs.len() as u64);
+    let entries = jobs
+        .into_par_iter()
+        .map(|(path, name, stamp, old)| {
+            let is_new = old.is_none();
+            let mut tiles = match old {
+                Some(entry) if entry.stamp == stamp => entry.tiles,
+                _ => vec![],
+            };
+            let mut image = None;
+            for (color_space, tile_fit, (width, height)) in
+                iter_combinations(color_spaces, tile_fits, sizes)
+            {
+                if find_tile(&tiles, color_space, tile_fit, width, height).is_some() {
+                    continue;
+                }
+                let image = match &image {
+                    Some(image) => image,
+                    None => match decode_tile(&path) {
+                        Ok(decoded) => image.insert(decoded),
+                        Err(e) => {
+                            skip(e, progress)?;
+                            progress.inc();
+                            return Ok((is_new, None));
+                        }
+                    },
+                };
+                let tile = prepare_tile(image, width, height, color_space, tile_fit);
+                tiles.push(CachedTile {
+                    color_space: color_space.to_string(),
+                    tile_fit: tile_fit.to_string(),
+                    thumbnail: DynamicImage::ImageRgb32F(tile.image).to_rgb8(),
+                    features: tile.features,
+                });
+            }
+            progress.inc();
+            Ok((is_new, Some((name, Entry { stamp, tiles }))))
+        })
+        .collect::<Result<Vec<_>, MosaicError>>()?;
+    progress.finish(Stage::Index);
+
+    let mut kept = BTreeMap::new();
+    for (is_new, entry) in entries {
+        match entry {
+            Some((name, entry)) => {
+                kept.insert(name, entry);
+            }
+            None => {
+                if is_new {
+                    summary.added -= 1;
+                } else {
+                    summary.updated -= 1;
+                }
+                summary.skipped += 1;
+            }
+        }
+    }
+    TileIndex {
+        directory: directory.to_path_buf(),
+        entries: kept,
+    }
+    .save(directory)?;
+    Ok(summary)
+}
+
+fn iter_combinations<'a>(
+    color_spaces: &'a [ColorSpace],
+    tile_fits: &'a [TileFit],
+    sizes: &'a [(u32, u32)],
+) -> impl Iterator<Item = (ColorSpace, TileFit, (u32, u32))> + 'a {
+    color_spaces.iter().flat_map(move |&c| {
+        tile_fits
+            .iter()
+            .flat_map(move |&f| sizes.iter().map(move |&s| (c, f, s)))
+    })
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+/// The product of `counts`, if that many items of `size` bytes each fit in
+/// the bytes left in `r`.
+fn checked_len<R>(r: &Take<R>, counts: &[u64], size: u64) -> io::Result<usize> {
+    counts
+        .iter()
+        .try_fold(1, |len: u64, &count| len.checked_mul(count))
+        .filter(|len| {
+            len.checked_mul(size)
+                .is_some_and(|bytes| bytes <= r.limit())
+        })
+        .and_then(|len| usize::try_from(len).ok())
+        .ok_or_else(|| io::ErrorKind::InvalidData.into())
+}
+
+fn read_string<R: Read>(r: &mut Take<R>) -> io::Result<String> {
+    let len = read_u32(r)?;
+    let mut buf = vec![0; checked_len(r, &[u64::from(len)], 1)?];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| io::ErrorKind::InvalidData.into())
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use image::Rgb;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::progress::Silent;
+
+    const SIZE: (u32, u32) = (6, 4);
+
+    fn write_image(path: &Path, width: u32, color: [u8; 3]) {
+        RgbImage::from_fn(width, 8, |x, y| {
+            Rgb(color.map(|c| c.wrapping_add((x * 7 + y * 3) as u8)))
+        })
+        .save(path)
+        .unwrap();
+    }
+
+    fn update(directory: &Path) -> [usize; 5] {
+        let summary = update_index(
+            directory,
+            &Scan::new(),
+            &[ColorSpace::Lab],
+            &[TileFit::Stretch],
+            &[SIZE],
+            &Silent,
+        )
+        .unwrap();
+        [
+            summary.added,
+            summary.updated,
+            summary.removed,
+            summary.unchanged,
+            summary.skipped,
+        ]
+    }
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn detects_added_updated_and_removed_files() {
+        let dir = TempDir::new().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        write_image(&path("a.png"), 12, [10, 20, 30]);
+        write_image(&path("b.png"), 12, [90, 60, 30]);
+        write_image(&path("c.png"), 12, [200, 0, 100]);
+        fs::write(path("notes.txt"), "not an image").unwrap();
+        assert_eq!(update(dir.path()), [3, 0, 0, 0, 1]);
+        assert_eq!(update(dir.path()), [0, 0, 0, 3, 1]);
+
+        // `a` only has a new modification time and `b` only a new size.
+        let modified = fs::metadata(path("a.png")).unwrap().modified().unwrap();
+        set_modified(&path("a.png"), modified + Duration::from_secs(60));
+        let modified = fs::metadata(path("b.png")).unwrap().modified().unwrap();
+        write_image(&path("b.png"), 16, [90, 60, 30]);
+        set_modified(&path("b.png"), modified);
+        fs::remove_file(path("c.png")).unwrap();
+        write_image(&path("d.png"), 12, [0, 0, 0]);
+        assert_eq!(update(dir.path()), [1, 2, 1, 0, 1]);
+        assert_eq!(update(dir.path()), [0, 0, 0, 3, 1]);
+    }
+
+    #[test]
+    fn cached_tiles_match_fresh_ones() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sub").join("a.png");
+        fs::create_dir(path.parent().unwrap()).unwrap();
+        write_image(&path, 12, [10, 120, 230]);
+        update(dir.path());
+
+        let index = TileIndex::load(dir.path());
+        let (width, height) = SIZE;
+        let cached = index
+            .lookup(&path, ColorSpace::Lab, TileFit::Stretch, width, height)
+            .expect("indexed");
+        let image = decode_tile(&path).unwrap();
+        let fresh = prepare_tile(&image, width, height, ColorSpace::Lab, TileFit::Stretch);
+        assert_eq!(cached.features.as_slice(), fresh.features.as_slice());
+        assert_eq!(
+            DynamicImage::ImageRgb32F(cached.image).to_rgb8(),
+            DynamicImage::ImageRgb32F(fresh.image).to_rgb8()
+        );
+        assert!(index
+            .lookup(&path, ColorSpace::Rgb, TileFit::Stretch, width, height)
+            .is_none());
+        assert!(index
+            .lookup(&path, ColorSpace::Lab, TileFit::Letterbox, width, height)
+            .is_none());
+
+        set_modified(&path, SystemTime::now() + Duration::from_secs(60));
+        assert!(index
+            .lookup(&path, ColorSpace::Lab, TileFit::Stretch, width, height)
+            .is_none());
+    }
+
+    #[test]
+    fn round_trips_through_the_file_format() {
+        let dir = TempDir::new().unwrap();
+        write_image(&dir.path().join("a.png"), 12, [10, 20, 30]);
+        write_image(&dir.path().join("b.png"), 12, [40, 50, 60]);
+        update(dir.path());
+        let bytes = fs::read(TileIndex::path(dir.path())).unwrap();
+        let entries = TileIndex::read(&mut bytes.as_slice().take(bytes.len() as u64)).unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["a.png", "b.png"]);
+        let mut written = vec![];
+        TileIndex {
+            directory: dir.path().to_path_buf(),
+            entries,
+        }
+        .write(&mut written)
+        .unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn unreadable_index_is_empty() {
+        let dir = TempDir::new().unwrap();
+        write_image(&dir.path().join("a.png"), 12, [10, 20, 30]);
+        update(dir.path());
+        let index = TileIndex::path(dir.path());
+        let bytes = fs::read(&index).unwrap();
+        assert!(!TileIndex::load(dir.path()).is_empty());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] ^= 1;
+        let mut wrong_version = bytes.clone();
+        wrong_version[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let truncated = bytes[..bytes.len() - 1].to_vec();
+        let header = |w: &mut Vec<u8>| {
+            w.extend(MAGIC);
+            w.extend(VERSION.to_le_bytes());
+            w.extend(1u64.to_le_bytes());
+        };
+        let mut long_name = vec![];
+        header(&mut long_name);
+        long_name.extend(u32::MAX.to_le_bytes());
+        let mut huge_thumbnail = vec![];
+        header(&mut huge_thumbnail);
+        write_string(&mut huge_thumbnail, "a.png").unwrap();
+        huge_thumbnail.extend([0; 20]);
+        huge_thumbnail.extend(1u32.to_le_bytes());
+        write_string(&mut huge_thumbnail, "lab").unwrap();
+        write_string(&mut huge_thumbnail, "stretch").unwrap();
+        huge_thumbnail.extend(u32::MAX.to_le_bytes());
+        huge_thumbnail.extend(u32::MAX.to_le_bytes());
+
+        for corrupted in [
+            wrong_magic,
+            wrong_version,
+            truncated,
+            long_name,
+            huge_thumbnail,
+        ] {
+            fs::write(&index, corrupted).unwrap();
+            assert!(TileIndex::load(dir.path()).is_empty());
+        }
+    }
+}
@@ -1,18 +1,63 @@
 use image::{Luma, LumaA, Pixel, Primitive, Rgb, Rgba};
+use num::NumCast;
 
-#[derive(Clone, Copy, Default)]
-pub(crate) struct Lab<T>(pub [T; 3]);
+/// A CIELAB colour with L* in 0..=100 and a*, b* roughly in -128..=127,
+/// relative to the D65 white point.
+///
+/// Geometric operations such as `imageops::flip_horizontal` work on Lab images
+/// directly, but the resampling filters in `imageops` clamp floating-point
+/// subpixels to 0..=1, so resize in RGB before converting.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+#[repr(transparent)]
+pub struct Lab<T>(pub [T; 3]);
 
-pub(crate) trait PixelLabExt {
+/// Converts a pixel to CIELAB.
+pub trait PixelLabExt {
     type Subpixel;
     fn to_lab(&self) -> Lab<Self::Subpixel>;
 }
 
-impl PixelLabExt for Rgb<f32> {
-    type Subpixel = f32;
+impl<T: Primitive> PixelLabExt for Rgb<T> {
+    type Subpixel = T;
     fn to_lab(&self) -> Lab<Self::Subpixel> {
         let Rgb(rgb) = self;
-        Lab(rgb2lab(rgb))
+        Lab::from_f32(rgb2lab(&rgb.map(to_unit)))
+    }
+}
+
+/// Scales a subpixel to 0..=1 using the nominal range of its type.
+fn to_unit<T: Primitive>(c: T) -> f32 {
+    let max = T::DEFAULT_MAX_VALUE.to_f32().unwrap_or(1.0);
+    c.to_f32().unwrap_or_default() / max
+}
+
+/// Inverse of `to_unit`, clamping to the nominal range of the type.
+fn from_unit<T: Primitive>(c: f32) -> T {
+    let max = T::DEFAULT_MAX_VALUE.to_f32().unwrap_or(1.0);
+    let c = c.clamp(0.0, 1.0) * max;
+    let c = if max > 1.0 { c.round() } else { c };
+    NumCast::from(c).unwrap_or(T::DEFAULT_MAX_VALUE)
+}
+
+impl<T: Primitive> Lab<T> {
+    fn to_f32(self) -> [f32; 3] {
+        self.0.map(|c| c.to_f32().unwrap_or_default())
+    }
+
+    fn from_f32(lab: [f32; 3]) -> Self {
+        Lab(lab.map(|c| {
+            let c = if T::DEFAULT_MAX_VALUE.to_f32().unwrap_or(1.0) > 1.0 {
+                c.round()
+            } else {
+                c
+            };
+            let saturated = if c < 0.0 {
+                T::DEFAULT_MIN_VALUE
+            } else {
+                T::DEFAULT_MAX_VALUE
+            };
+            NumCast::from(c).unwrap_or(saturated)
+        }))
     }
 }
 
@@ -39,7 +84,8 @@ impl<T: Primitive> Pixel for Lab<T> {
         Self::Subpixel,
         Self::Subpixel,
     ) {
-        unimplemented!()
+        let [l, a, b] = self.0;
+        (l, a, b, T::DEFAULT_MAX_VALUE)
     }
 
     fn from_channels(
@@ -48,53 +94,56 @@ impl<T: Primitive> Pixel for Lab<T> {
         c: Self::Subpixel,
         d: Self::Subpixel,
     ) -> Self {
-        let _ = a;
-        let _ = b;
-        let _ = c;
         let _ = d;
-        unimplemented!()
+        Lab([a, b, c])
     }
 
     fn from_slice(slice: &[Self::Subpixel]) -> &Self {
-        let _ = slice;
-        todo!()
+        assert_eq!(slice.len(), 3);
+        // SAFETY: `Lab<T>` is `repr(transparent)` over `[T; 3]` and the length was checked.
+        unsafe { &*(slice.as_ptr() as *const Lab<T>) }
     }
 
     fn from_slice_mut(slice: &mut [Self::Subpixel]) -> &mut Self {
-        let _ = slice;
-        todo!()
+        assert_eq!(slice.len(), 3);
+        // SAFETY: `Lab<T>` is `repr(transparent)` over `[T; 3]` and the length was checked.
+        unsafe { &mut *(slice.as_mut_ptr() as *mut Lab<T>) }
     }
 
     fn to_rgb(&self) -> Rgb<Self::Subpixel> {
-        todo!()
+        Rgb(lab2rgb(&self.to_f32()).map(from_unit))
     }
 
     fn to_rgba(&self) -> Rgba<Self::Subpixel> {
-        todo!()
+        let Rgb([r, g, b]) = self.to_rgb();
+        Rgba([r, g, b, T::DEFAULT_MAX_VALUE])
     }
 
+    /// Uses the same Rec. 709 weights as `Rgb::to_luma`.
     fn to_luma(&self) -> Luma<Self::Subpixel> {
-        todo!()
+        let [r, g, b] = lab2rgb(&self.to_f32()).map(|c| c.clamp(0.0, 1.0));
+        Luma([from_unit(0.2126 * r + 0.7152 * g + 0.0722 * b)])
     }
 
     fn to_luma_alpha(&self) -> LumaA<Self::Subpixel> {
-        todo!()
+        let Luma([l]) = self.to_luma();
+        LumaA([l, T::DEFAULT_MAX_VALUE])
     }
 
     fn map<F>(&self, f: F) -> Self
     where
         F: FnMut(Self::Subpixel) -> Self::Subpixel,
     {
-        let _ = f;
-        todo!()
+        Lab(self.0.map(f))
     }
 
-    fn apply<F>(&mut self, f: F)
+    fn apply<F>(&mut self, mut f: F)
     where
         F: FnMut(Self::Subpixel) -> Self::Subpixel,
     {
-        let _ = f;
-        todo!()
+        for c in &mut self.0 {
+            *c = f(*c);
+        }
     }
 
     fn map_with_alpha<F, G>(&self, f: F, g: G) -> Self
@@ -102,9 +151,8 @@ impl<T: Primitive> Pixel for Lab<T> {
         F: FnMut(Self::Subpixel) -> Self::Subpixel,
         G: FnMut(Self::Subpixel) -> Self::Subpixel,
     {
-        let _ = f;
         let _ = g;
-        todo!()
+        self.map(f)
     }
 
     fn apply_with_alpha<F, G>(&mut self, f: F, g: G)
@@ -112,36 +160,37 @@ impl<T: Primitive> Pixel for Lab<T> {
         F: FnMut(Self::Subpixel) -> Self::Subpixel,
         G: FnMut(Self::Subpixel) -> Self::Subpixel,
     {
-        let _ = f;
         let _ = g;
-        todo!()
+        self.apply(f);
     }
 
     fn map2<F>(&self, other: &Self, f: F) -> Self
     where
         F: FnMut(Self::Subpixel, Self::Subpixel) -> Self::Subpixel,
     {
-        let _ = other;
-        let _ = f;
-        todo!()
+        let mut this = *self;
+        this.apply2(other, f);
+        this
     }
 
-    fn apply2<F>(&mut self, other: &Self, f: F)
+    fn apply2<F>(&mut self, other: &Self, mut f: F)
     where
         F: FnMut(Self::Subpixel, Self::Subpixel) -> Self::Subpixel,
     {
-        let _ = other;
-        let _ = f;
-        todo!()
+        for (a, &b) in self.0.iter_mut().zip(&other.0) {
+            *a = f(*a, b);
+        }
     }
 
+    /// Inverts the colour in RGB, like `Rgb::invert`.
     fn invert(&mut self) {
-        todo!()
+        let rgb = lab2rgb(&self.to_f32()).map(|c| 1.0 - c.clamp(0.0, 1.0));
+        *self = Lab::from_f32(rgb2lab(&rgb));
     }
 
+    /// Without an alpha channel the other pixel fully covers this one.
     fn blend(&mut self, other: &Self) {
-        let _ = other;
-        todo!()
+        *self = *other;
     }
 
     fn map_without_alpha<F>(&self, f: F) -> Self
@@ -162,9 +211,9 @@ impl<T: Primitive> Pixel for Lab<T> {
 }
 
 /// https://en.wikipedia.org/wiki/CIELAB_color_space
-/// lを２倍に
-fn rgb2lab(rgb: &[f32; 3]) -> [f32; 3] {
-    let [mut r, mut g, mut b] = rgb.map(|c| c / 255.0);
+/// Converts sRGB (0..=1) to L*a*b*.
+pub(crate) fn rgb2lab(rgb: &[f32; 3]) -> [f32; 3] {
+    let [mut r, mut g, mut b] = *rgb;
     r = if r > 0.04045 {
         f32::powf((r + 0.055) / 1.055, 2.4)
     } else {
@@ -201,6 +250,127 @@ fn rgb2lab(rgb: &[f32; 3]) -> [f32; 3] {
     let l = (116.0 * y) - 16.0;
     let a = 500.0 * (x - y);
     let b = 200.0 * (y - z);
-    [2.0 * l, a, b]
+    [l, a, b]
 }
 
+/// Inverse of `rgb2lab`. The result is not clamped to 0..=1.
+pub(crate) fn lab2rgb(lab: &[f32; 3]) -> [f32; 3] {
+    let [l, a, b] = *lab;
+    let mut y = (l + 16.0) / 116.0;
+    let mut x = a / 500.0 + y;
+    let mut z = y - b / 200.0;
+    x = if x.powi(3) > 0.008856 {
+        x.powi(3)
+    } else {
+        (x - 16.0 / 116.0) / 7.787
+    };
+    y = if y.powi(3) > 0.008856 {
+        y.powi(3)
+    } else {
+        (y - 16.0 / 116.0) / 7.787
+    };
+    z = if z.powi(3) > 0.008856 {
+        z.powi(3)
+    } else {
+        (z - 16.0 / 116.0) / 7.787
+    };
+    x *= 0.95047;
+    y *= 1.00000;
+    z *= 1.08883;
+    let rgb = [
+        x * 3.2406 + y * -1.5372 + z * -0.4986,
+        x * -0.9689 + y * 1.8758 + z * 0.0415,
+        x * 0.0557 + y * -0.2040 + z * 1.0570,
+    ];
+    rgb.map(|c| {
+        if c > 0.0031308 {
+            1.055 * f32::powf(c, 1.0 / 2.4) - 0.055
+        } else {
+            c * 12.92
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{imageops, ImageBuffer, Pixel, Rgb};
+
+    use super::*;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3], tolerance: f32) {
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!(
+                (a - e).abs() <= tolerance,
+                "{actual:?} is not within {tolerance} of {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rgb2lab_matches_reference_values() {
+        // L*a*b* under D65 of the sRGB colours, as published by Bruce Lindbloom.
+        let cases = [
+            ([1.0, 1.0, 1.0], [100.0, 0.0, 0.0]),
+            ([0.0, 0.0, 0.0], [0.0, 0.0, 0.0]),
+            ([1.0, 0.0, 0.0], [53.2408, 80.0925, 67.2032]),
+            ([0.0, 1.0, 0.0], [87.7347, -86.1827, 83.1793]),
+            ([0.0, 0.0, 1.0], [32.2970, 79.1875, -107.8602]),
+            ([0.5, 0.5, 0.5], [53.3890, 0.0, 0.0]),
+        ];
+        for (rgb, lab) in cases {
+            assert_close(rgb2lab(&rgb), lab, 0.05);
+        }
+    }
+
+    #[test]
+    fn lab2rgb_inverts_rgb2lab() {
+        let steps = (0..=10).map(|i| i as f32 / 10.0);
+        for r in steps.clone() {
+            for g in steps.clone() {
+                for b in steps.clone() {
+                    assert_close(lab2rgb(&rgb2lab(&[r, g, b])), [r, g, b], 2e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_converts_to_and_from_rgb() {
+        let red = Rgb([255u8, 0, 0]);
+        assert_eq!(red.to_lab(), Lab([53, 80, 67]));
+        let red = Rgb([1.0f32, 0.0, 0.0]).to_lab().to_rgb();
+        assert_close(red.0, [1.0, 0.0, 0.0], 1e-3);
+        assert_eq!(Lab([100.0f32, 0.0, 0.0]).to_luma().0[0].round(), 1.0);
+
+        let mut white = Rgb([1.0f32; 3]).to_lab();
+        white.invert();
+        assert_close(white.0, [0.0; 3], 0.05);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn pixel_channels() {
+        assert_eq!(Lab([1.0f32, 2.0, 3.0]).channels4(), (1.0, 2.0, 3.0, 1.0));
+        assert_eq!(
+            Lab::from_channels(1.0f32, 2.0, 3.0, 4.0),
+            Lab([1.0, 2.0, 3.0])
+        );
+    }
+
+    #[test]
+    fn image_buffer_of_lab_pixels() {
+        let pixels = [[10.0f32, 1.0, -1.0], [20.0, 2.0, -2.0], [30.0, 3.0, -3.0]];
+        let raw = pixels.iter().flatten().copied().collect::<Vec<_>>();
+        let mut image = ImageBuffer::<Lab<f32>, _>::from_raw(3, 1, raw).expect("3x1 pixels");
+        assert_eq!(*image.get_pixel(1, 0), Lab(pixels[1]));
+
+        let flipped = imageops::flip_horizontal(&image);
+        for (x, pixel) in pixels.iter().rev().enumerate() {
+            assert_eq!(*flipped.get_pixel(x as u32, 0), Lab(*pixel));
+        }
+
+        image.get_pixel_mut(0, 0).0[0] = 50.0;
+        assert_eq!(image.as_raw()[0], 50.0);
+        assert_eq!(*Lab::from_slice(&[1.0f32, 2.0, 3.0]), Lab([1.0, 2.0, 3.0]));
+    }
+}
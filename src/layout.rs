@@ -0,0 +1,532 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use image::{
+    imageops::{crop_imm, resize, FilterType::Lanczos3},
+    GrayImage, Luma, Rgb32FImage,
+};
+
+use crate::structure::Shape;
+
+/// A rectangle of the target, in pixels, covered by one tile. It may extend
+/// past the edges of the target, in which case the part outside is matched
+/// against the nearest edge of the target and left out of the mosaic.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub x: i64,
+    pub y: i64,
+    pub width: u32,
+    pub height: u32,
+    /// Grid coordinates passed to placement constraints.
+    pub cell: (u32, u32),
+}
+
+/// Divides the target into regions that each receive one tile.
+///
+/// The cells of the `row_size` x `col_size` grid set the size of the tiles,
+/// which the layout may arrange differently or subdivide.
+pub trait Layout: Send + Sync {
+    /// Adjusts the size of a cell, for layouts that can only divide some sizes.
+    fn cell_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (width, height)
+    }
+
+    /// Regions covering all of `target` with cells of `width` x `height`.
+    fn regions(&self, target: &Rgb32FImage, width: u32, height: u32) -> Vec<Region>;
+
+    /// The pixels of a `width` x `height` region covered by its cell, as
+    /// non-zero values, or `None` if it covers the whole rectangle.
+    fn mask(&self, width: u32, height: u32) -> Option<GrayImage> {
+        let _ = (width, height);
+        None
+    }
+}
+
+/// A uniform grid of cells.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rectangular;
+
+impl Layout for Rectangular {
+    fn regions(&self, target: &Rgb32FImage, width: u32, height: u32) -> Vec<Region> {
+        let mut regions = vec![];
+        for row in 0..target.height() / height {
+            for column in 0..target.width() / width {
+                regions.push(Region {
+                    x: (column * width).into(),
+                    y: (row * height).into(),
+                    width,
+                    height,
+                    cell: (column, row),
+                });
+            }
+        }
+        regions
+    }
+}
+
+/// A running bond, like a brick wall: every other row is shifted by half a cell.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Brick;
+
+impl Layout for Brick {
+    fn regions(&self, target: &Rgb32FImage, width: u32, height: u32) -> Vec<Region> {
+        offset_rows(target, width, height, height)
+    }
+}
+
+/// A honeycomb of pointy-top hexagons inscribed in the cells. Rows overlap
+/// by a quarter of a cell and every other row is shifted by half a cell.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Hexagonal;
+
+impl Layout for Hexagonal {
+    fn cell_size(&self, width: u32, height: u32) -> (u32, u32) {
+        (width / 2 * 2, height / 4 * 4)
+    }
+
+    fn regions(&self, target: &Rgb32FImage, width: u32, height: u32) -> Vec<Region> {
+        offset_rows(target, width, height, height / 4 * 3)
+    }
+
+    /// Assigns each pixel to the nearest centre of the hexagonal lattice,
+    /// measured after scaling the cell to a regular hexagon. Ties go to the
+    /// centre that comes first in reading order, so that neighbouring masks
+    /// never overlap or leave gaps.
+    fn mask(&self, width: u32, height: u32) -> Option<GrayImage> {
+        let (w, h) = (i64::from(width), i64::from(height));
+        // Doubled coordinates keep the lattice and the pixel centres integral.
+        let offsets = [
+            (-w, -3 * h / 2),
+            (w, -3 * h / 2),
+            (-2 * w, 0),
+            (2 * w, 0),
+            (-w, 3 * h / 2),
+            (w, 3 * h / 2),
+        ];
+        let distance = |dx: i64, dy: i64| 3 * h * h * dx * dx + 4 * w * w * dy * dy;
+        Some(GrayImage::from_fn(width, height, |x, y| {
+            let (px, py) = (2 * i64::from(x) + 1 - w, 2 * i64::from(y) + 1 - h);
+            let own = distance(px, py);
+            let inside = offsets.iter().all(|&(ox, oy)| {
+                let other = distance(px - ox, py - oy);
+                other > own || (other == own && (oy, ox) > (0, 0))
+            });
+            Luma([if inside { 255 } else { 0 }])
+        }))
+    }
+}
+
+/// Rows of `width` x `height` cells, `step` apart, with every other row
+/// shifted by half a cell, covering `target` with a margin of one cell.
+fn offset_rows(target: &Rgb32FImage, width: u32, height: u32, step: u32) -> Vec<Region> {
+    let (w, h, step) = (i64::from(width), i64::from(height), i64::from(step));
+    let (target_width, target_height) = (i64::from(target.width()), i64::from(target.height()));
+    let mut regions = vec![];
+    let mut row = 0;
+    loop {
+        let y = i64::from(row) * step - h;
+        if y >= target_height {
+            break;
+        }
+        let shift = if row % 2 == 1 { w / 2 } else { 0 };
+        let mut column = 0;
+        loop {
+            let x = i64::from(column) * w + shift - w;
+            if x >= target_width {
+                break;
+            }
+            if x + w > 0 && y + h > 0 {
+                regions.push(Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                    cell: (column, row),
+                });
+            }
+            column += 1;
+        }
+        row += 1;
+    }
+    regions
+}
+
+/// The layouts that can be chosen from the command line.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum LayoutKind {
+    /// See [`Rectangular`].
+    #[default]
+    Grid,
+    /// See [`Quadtree`].
+    Quadtree,
+    /// See [`Brick`].
+    Brick,
+    /// See [`Hexagonal`].
+    Hex,
+}
+
+impl ValueEnum for LayoutKind {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            LayoutKind::Grid,
+            LayoutKind::Quadtree,
+            LayoutKind::Brick,
+            LayoutKind::Hex,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            LayoutKind::Grid => {
+                PossibleValue::new("grid").help("A uniform grid of rectangular tiles.")
+            }
+            LayoutKind::Quadtree => PossibleValue::new("quadtree")
+                .help("Grid cells split into smaller tiles where the target is detailed."),
+            LayoutKind::Brick => PossibleValue::new("brick")
+                .help("Rows of rectangular tiles, every other row shifted by half a tile."),
+            LayoutKind::Hex => PossibleValue::new("hex").help("A honeycomb of hexagonal tiles."),
+        })
+    }
+}
+
+impl std::fmt::Display for LayoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// How the target is scaled to the size of the grid, which rarely has the
+/// same aspect ratio since its cells are whole pixels.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Fit {
+    /// Scale each axis separately, slightly distorting the target.
+    #[default]
+    Stretch,
+    /// Scale uniformly until the grid is covered, cropping the excess evenly
+    /// from both sides.
+    Cover,
+    /// Scale uniformly until the target fits inside the grid, extending its
+    /// edges over the remainder.
+    Contain,
+}
+
+impl ValueEnum for Fit {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Fit::Stretch, Fit::Cover, Fit::Contain]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Fit::Stretch => PossibleValue::new("stretch")
+                .help("Scale the width and height separately, slightly distorting the target."),
+            Fit::Cover => PossibleValue::new("cover")
+                .help("Keep the aspect ratio and crop the edges that do not fit."),
+            Fit::Contain => PossibleValue::new("contain")
+                .help("Keep the aspect ratio and pad the sides by extending the edges."),
+        })
+    }
+}
+
+impl std::fmt::Display for Fit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl Fit {
+    /// Resizes `target` to exactly `width` x `height`.
+    pub(crate) fn apply(self, target: &Rgb32FImage, width: u32, height: u32) -> Rgb32FImage {
+        let (target_width, target_height) = target.dimensions();
+        let scale_x = f64::from(width) / f64::from(target_width);
+        let scale_y = f64::from(height) / f64::from(target_height);
+        let scaled = |scale: f64| {
+            let w = (f64::from(target_width) * scale).round() as u32;
+            let h = (f64::from(target_height) * scale).round() as u32;
+            (w, h)
+        };
+        match self {
+            Fit::Stretch => resize(target, width, height, Lanczos3),
+            Fit::Cover => {
+                let (w, h) = scaled(scale_x.max(scale_y));
+                let (w, h) = (w.max(width), h.max(height));
+                let scaled = resize(target, w, h, Lanczos3);
+                crop_imm(&scaled, (w - width) / 2, (h - height) / 2, width, height).to_image()
+            }
+            Fit::Contain => {
+                let (w, h) = scaled(scale_x.min(scale_y));
+                let (w, h) = (w.clamp(1, width), h.clamp(1, height));
+                let scaled = resize(target, w, h, Lanczos3);
+                let (left, top) = ((width - w) / 2, (height - h) / 2);
+                extend(&scaled, left, top, width - w - left, height - h - top)
+            }
+        }
+    }
+}
+
+/// Extends `image` by the given margins, repeating its edge pixels.
+pub(crate) fn extend(
+    image: &Rgb32FImage,
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+) -> Rgb32FImage {
+    let (width, height) = image.dimensions();
+    Rgb32FImage::from_fn(left + width + right, top + height + bottom, |x, y| {
+        let x = x.saturating_sub(left).min(width - 1);
+        let y = y.saturating_sub(top).min(height - 1);
+        *image.get_pixel(x, y)
+    })
+}
+
+/// How the detail of a region is measured when deciding whether to split it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Detail {
+    /// Standard deviation of the luma.
+    #[default]
+    Variance,
+    /// Mean magnitude of the Sobel gradient of the luma.
+    Edges,
+}
+
+impl ValueEnum for Detail {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Detail::Variance, Detail::Edges]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Detail::Variance => {
+                PossibleValue::new("variance").help("Standard deviation of the brightness.")
+            }
+            Detail::Edges => PossibleValue::new("edges")
+                .help("Mean strength of the edges found with a Sobel filter."),
+        })
+    }
+}
+
+impl std::fmt::Display for Detail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// The cells of a grid, split into quarters while their detail is above a
+/// threshold, so detailed areas get smaller tiles.
+#[derive(Clone, Copy, Debug)]
+pub struct Quadtree {
+    detail: Detail,
+    threshold: f32,
+    min_tile_size: u32,
+}
+
+impl Quadtree {
+    /// Splits regions whose `detail` is above `threshold`, but never into
+    /// tiles with a side shorter than `min_tile_size` pixels.
+    pub fn new(detail: Detail, threshold: f32, min_tile_size: u32) -> Self {
+        Self {
+            detail,
+            threshold,
+            min_tile_size: min_tile_size.max(1),
+        }
+    }
+
+    /// How many times a `width` x `height` cell can be halved.
+    fn levels(&self, width: u32, height: u32) -> u32 {
+        let mut levels = 0;
+        while (width >> (levels + 1)).min(height >> (levels + 1)) >= self.min_tile_size {
+            levels += 1;
+        }
+        levels
+    }
+
+    fn measure(&self, shape: &Shape, region: &Region) -> f32 {
+        let value = |x: i64, y: i64| match self.detail {
+            Detail::Variance => shape.at(x as isize, y as isize),
+            Detail::Edges => {
+                let (gx, gy) = shape.gradient(x as usize, y as usize);
+                gx.hypot(gy)
+            }
+        };
+        let n = (region.width * region.height) as f32;
+        let (mut sum, mut squares) = (0.0, 0.0);
+        for y in region.y..region.y + i64::from(region.height) {
+            for x in region.x..region.x + i64::from(region.width) {
+                let v = value(x, y);
+                sum += v;
+                squares += v * v;
+            }
+        }
+        let mean = sum / n;
+        match self.detail {
+            Detail::Variance => (squares / n - mean * mean).max(0.0).sqrt(),
+            Detail::Edges => mean,
+        }
+    }
+}
+
+impl Default for Quadtree {
+    /// Splits regions whose luma has a standard deviation above 0.1, down to
+    /// tiles of 8 pixels.
+    fn default() -> Self {
+        Self::new(Detail::Variance, 0.1, 8)
+    }
+}
+
+impl Layout for Quadtree {
+    /// Trims the sides to multiples of `2^levels` so that cells halve evenly.
+    fn cell_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let levels = self.levels(width, height);
+        (width >> levels << levels, height >> levels << levels)
+    }
+
+    fn regions(&self, target: &Rgb32FImage, width: u32, height: u32) -> Vec<Region> {
+        let levels = self.levels(width, height);
+        // Cells are counted in units of the smallest possible region.
+        let unit_width = i64::from(width >> levels);
+        let unit_height = i64::from(height >> levels);
+        let region = |x: i64, y: i64, width, height| Region {
+            x,
+            y,
+            width,
+            height,
+            cell: ((x / unit_width) as u32, (y / unit_height) as u32),
+        };
+        let shape = Shape::new(target);
+        let mut regions = vec![];
+        let mut pending = Rectangular
+            .regions(target, width, height)
+            .into_iter()
+            .map(|r| (region(r.x, r.y, width, height), 0))
+            .rev()
+            .collect::<Vec<_>>();
+        while let Some((parent, level)) = pending.pop() {
+            if level == levels || self.measure(&shape, &parent) <= self.threshold {
+                regions.push(parent);
+                continue;
+            }
+            let (width, height) = (parent.width / 2, parent.height / 2);
+            for (dx, dy) in [(1, 1), (0, 1), (1, 0), (0, 0)] {
+                let x = parent.x + dx * i64::from(width);
+                let y = parent.y + dy * i64::from(height);
+                pending.push((region(x, y, width, height), level + 1));
+            }
+        }
+        regions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    /// How many regions cover each pixel of the target, counting only the
+    /// pixels inside their masks.
+    fn coverage(layout: &impl Layout, target: &Rgb32FImage, width: u32, height: u32) -> Vec<u32> {
+        let (target_width, target_height) = target.dimensions();
+        let mut counts = vec![0; (target_width * target_height) as usize];
+        for region in layout.regions(target, width, height) {
+            let mask = layout.mask(region.width, region.height);
+            for y in 0..region.height {
+                for x in 0..region.width {
+                    let (tx, ty) = (region.x + i64::from(x), region.y + i64::from(y));
+                    let outside = tx < 0
+                        || ty < 0
+                        || tx >= i64::from(target_width)
+                        || ty >= i64::from(target_height);
+                    let masked = mask.as_ref().is_some_and(|m| m.get_pixel(x, y).0[0] == 0);
+                    if !outside && !masked {
+                        counts[(ty * i64::from(target_width) + tx) as usize] += 1;
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    fn flat(width: u32, height: u32) -> Rgb32FImage {
+        Rgb32FImage::from_pixel(width, height, Rgb([0.5, 0.5, 0.5]))
+    }
+
+    #[test]
+    fn offset_layouts_cover_every_pixel_once() {
+        let target = flat(61, 47);
+        for (width, height) in [(8, 8), (10, 12), (6, 4), (2, 4), (14, 20)] {
+            let counts = coverage(&Brick, &target, width, height);
+            assert!(counts.iter().all(|&c| c == 1), "brick {width}x{height}");
+            let (width, height) = Hexagonal.cell_size(width, height);
+            let counts = coverage(&Hexagonal, &target, width, height);
+            assert!(counts.iter().all(|&c| c == 1), "hex {width}x{height}");
+        }
+    }
+
+    #[test]
+    fn hexagonal_cells_are_trimmed() {
+        assert_eq!(Hexagonal.cell_size(8, 8), (8, 8));
+        assert_eq!(Hexagonal.cell_size(7, 13), (6, 12));
+        assert_eq!(Hexagonal.cell_size(1, 3), (0, 0));
+        assert_eq!(Brick.cell_size(7, 13), (7, 13));
+    }
+
+    /// A checkerboard of 2 x 2 squares in the left `detailed` columns, and
+    /// flat grey elsewhere.
+    fn checkered(width: u32, height: u32, detailed: u32) -> Rgb32FImage {
+        Rgb32FImage::from_fn(width, height, |x, y| {
+            let v = if x < detailed {
+                ((x / 2 + y / 2) % 2) as f32
+            } else {
+                0.5
+            };
+            Rgb([v, v, v])
+        })
+    }
+
+    #[test]
+    fn quadtree_keeps_flat_cells_whole() {
+        let quadtree = Quadtree::new(Detail::Variance, 0.1, 4);
+        let regions = quadtree.regions(&flat(32, 32), 16, 16);
+        assert_eq!(regions.len(), 4);
+        assert!(regions.iter().all(|r| (r.width, r.height) == (16, 16)));
+    }
+
+    #[test]
+    fn quadtree_splits_detail_down_to_the_smallest_tile() {
+        for detail in [Detail::Variance, Detail::Edges] {
+            let quadtree = Quadtree::new(detail, 0.1, 4);
+            let regions = quadtree.regions(&checkered(32, 32, 32), 16, 16);
+            assert_eq!(regions.len(), 64, "{detail}");
+            assert!(regions.iter().all(|r| (r.width, r.height) == (4, 4)));
+        }
+    }
+
+    #[test]
+    fn quadtree_leaves_tile_the_target() {
+        let quadtree = Quadtree::new(Detail::Variance, 0.1, 4);
+        let target = checkered(64, 48, 20);
+        let regions = quadtree.regions(&target, 16, 16);
+        let sizes = regions.iter().map(|r| r.width).collect::<Vec<_>>();
+        assert!(sizes.contains(&16) && sizes.contains(&4));
+        let counts = coverage(&quadtree, &target, 16, 16);
+        assert!(counts.iter().all(|&c| c == 1));
+        // Cells are counted in units of the smallest tile.
+        assert!(regions
+            .iter()
+            .all(|r| r.cell == ((r.x / 4) as u32, (r.y / 4) as u32)));
+    }
+
+    #[test]
+    fn quadtree_cells_are_trimmed_to_halve_evenly() {
+        let quadtree = Quadtree::new(Detail::Variance, 0.1, 4);
+        assert_eq!(quadtree.cell_size(30, 20), (28, 20));
+        assert_eq!(quadtree.cell_size(16, 16), (16, 16));
+        assert_eq!(quadtree.cell_size(7, 3), (7, 3));
+    }
+}
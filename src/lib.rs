@@ -0,0 +1,40 @@
+mod assignment;
+mod augment;
+mod blend;
+mod color;
+mod descriptor;
+mod error;
+mod features;
+mod index;
+mod lab;
+mod layout;
+mod matcher;
+mod metric;
+mod mosaic;
+mod progress;
+mod scan;
+mod source;
+mod structure;
+mod tile_fit;
+
+pub use assignment::Assignment;
+pub use augment::Augment;
+pub use blend::BlendMode;
+pub use descriptor::Descriptor;
+pub use error::MosaicError;
+pub use index::{update_index, IndexSummary};
+pub use lab::{Lab, PixelLabExt};
+pub use layout::{
+    Brick, Detail, Fit, Hexagonal, Layout, LayoutKind, Quadtree, Rectangular, Region,
+};
+pub use matcher::Search;
+pub use metric::DistanceMetric;
+pub use mosaic::{
+    CellDistance, ColorSpace, DuplicatePolicy, MaxUses, MinDistance, MosaicBuilder,
+    PlacementConstraint,
+};
+pub use progress::{Progress, Silent, Stage};
+pub use scan::Scan;
+pub use source::TileSource;
+pub use structure::Structure;
+pub use tile_fit::TileFit;
@@ -1,31 +1,279 @@
-use std::path::Path;
+use std::{path::PathBuf, process::ExitCode, sync::Mutex};
 
-mod lab;
 mod clap;
-mod mosaic;
 
-use mosaic::{mosaic, ColorSpace};
+use ::clap::ArgMatches;
 use clap::get_matches;
+use indicatif::ProgressBar;
+use mosaicify::{
+    update_index, Assignment, Augment, BlendMode, Brick, CellDistance, ColorSpace, Descriptor,
+    Detail, DistanceMetric, DuplicatePolicy, Fit, Hexagonal, LayoutKind, MaxUses, MinDistance,
+    MosaicBuilder, MosaicError, Progress, Quadtree, Rectangular, Scan, Search, Stage, Structure,
+    TileFit, TileSource,
+};
 
-fn main() {
+struct Console {
+    pb: Mutex<Option<ProgressBar>>,
+    verbose: bool,
+}
+
+impl Console {
+    fn new(verbose: bool) -> Self {
+        Self {
+            pb: Mutex::new(None),
+            verbose,
+        }
+    }
+}
+
+impl Progress for Console {
+    fn start(&self, stage: Stage, len: u64) {
+        let action = stage.action();
+        let action = action[..1].to_uppercase() + &action[1..];
+        println!("[{}/{}] {action} {stage}.", stage.number(), stage.total());
+        *self.pb.lock().unwrap() = Some(ProgressBar::new(len));
+    }
+
+    fn inc(&self) {
+        if let Some(pb) = self.pb.lock().unwrap().as_ref() {
+            pb.inc(1);
+        }
+    }
+
+    fn finish(&self, stage: Stage) {
+        if let Some(pb) = self.pb.lock().unwrap().take() {
+            pb.finish_and_clear();
+        }
+        let action = stage.action();
+        println!(
+            "[{}/{}] Finished {action} {stage}.",
+            stage.number(),
+            stage.total()
+        );
+    }
+
+    fn message(&self, message: &str) {
+        if self.verbose {
+            println!("{message}");
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        eprintln!("Warning: {message}");
+    }
+}
+
+fn exit_code(error: &MosaicError) -> u8 {
+    match error {
+        MosaicError::MissingTarget
+        | MosaicError::MissingTiles
+        | MosaicError::IncompatibleMetric { .. }
+        | MosaicError::ConstraintsWithOptimal
+        | MosaicError::OptimalWithDuplicates
+        | MosaicError::AssignmentTooLarge { .. }
+        | MosaicError::InvalidPattern { .. } => 2,
+        MosaicError::InvalidGrid
+        | MosaicError::GridLargerThanTarget { .. }
+        | MosaicError::CellTooSmall { .. } => 3,
+        MosaicError::TargetRead { .. } | MosaicError::TargetDecode { .. } => 4,
+        MosaicError::TileRead { .. } | MosaicError::TileDecode { .. } => 5,
+        MosaicError::EmptyTileSet => 6,
+        MosaicError::NoMatch | MosaicError::ConstraintUnsatisfied { .. } => 7,
+        MosaicError::Save { .. } => 8,
+        MosaicError::IndexWrite { .. } => 9,
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => {
+            println!("All done.");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+fn run() -> Result<(), MosaicError> {
     let matches = get_matches();
+    if let Some(("index", matches)) = matches.subcommand() {
+        return index(matches);
+    }
     let target = matches.get_one::<String>("target").expect("required");
     let row_size = *matches.get_one::<u32>("row_size").expect("required");
     let col_size = *matches.get_one::<u32>("col_size").expect("required");
-    let images = matches.get_one::<String>("images").expect("required");
+    let images = matches
+        .get_many::<String>("images")
+        .expect("required")
+        .map(TileSource::from_path)
+        .collect();
     let output = matches.get_one::<String>("output").expect("required");
     let color_space = matches
         .get_one::<ColorSpace>("color_space")
         .expect("required");
-    let avoid_duplicates = matches.get_flag("avoid_duplicates");
-
-    mosaic(
-        Path::new(target),
-        row_size,
-        col_size,
-        Path::new(images),
-        Path::new(output),
-        *color_space,
-        avoid_duplicates,
+    let metric = *matches
+        .get_one::<DistanceMetric>("metric")
+        .expect("required");
+    let channel_weights = *matches
+        .get_one::<[f32; 3]>("channel_weights")
+        .expect("required");
+    let chroma_weight = *matches.get_one::<f32>("chroma_weight").expect("required");
+    let layout = *matches.get_one::<LayoutKind>("layout").expect("required");
+    let fit = *matches.get_one::<Fit>("fit").expect("required");
+    let tile_fit = *matches.get_one::<TileFit>("tile_fit").expect("required");
+    let detail = *matches.get_one::<Detail>("detail").expect("required");
+    let detail_threshold = *matches
+        .get_one::<f32>("detail_threshold")
+        .expect("required");
+    let min_tile_size = *matches.get_one::<u32>("min_tile_size").expect("required");
+    let descriptor = *matches
+        .get_one::<Descriptor>("descriptor")
+        .expect("required");
+    let search = *matches.get_one::<Search>("search").expect("required");
+    let candidates = *matches.get_one::<usize>("candidates").expect("required");
+    let assignment = *matches
+        .get_one::<Assignment>("assignment")
+        .expect("required");
+    let blend = *matches.get_one::<f32>("blend").expect("required");
+    let blend_mode = *matches
+        .get_one::<BlendMode>("blend_mode")
+        .expect("required");
+    let verbose = matches.get_flag("verbose");
+    let duplicates = if matches.get_flag("avoid_duplicates") {
+        DuplicatePolicy::Avoid
+    } else {
+        DuplicatePolicy::Allow
+    };
+
+    let builder = MosaicBuilder::new()
+        .target(target)
+        .grid(row_size, col_size)
+        .tiles(TileSource::Many(images))
+        .scan(scan(&matches))
+        .color_space(*color_space)
+        .metric(metric)
+        .channel_weights(channel_weights)
+        .chroma_weight(chroma_weight)
+        .fit(fit)
+        .tile_fit(tile_fit)
+        .augment(augment(&matches))
+        .descriptor(descriptor)
+        .duplicates(duplicates)
+        .search(search)
+        .candidates(candidates)
+        .assignment(assignment)
+        .blend(blend, blend_mode)
+        .progress(Console::new(verbose));
+    let builder = match layout {
+        LayoutKind::Grid => builder.layout(Rectangular),
+        LayoutKind::Quadtree => {
+            builder.layout(Quadtree::new(detail, detail_threshold, min_tile_size))
+        }
+        LayoutKind::Brick => builder.layout(Brick),
+        LayoutKind::Hex => builder.layout(Hexagonal),
+    };
+    let builder = match matches.get_one::<(u32, u32)>("tile_size") {
+        Some(&(width, height)) => builder.render_tile_size(width, height),
+        None => match matches.get_one::<u32>("output_width") {
+            Some(&width) => builder.output_width(width),
+            None => builder,
+        },
+    };
+    let builder = match matches.get_one::<usize>("max_memory") {
+        Some(&bytes) => builder.max_memory(bytes),
+        None => builder,
+    };
+    let builder = match matches.get_one::<u32>("match_resolution") {
+        Some(&resolution) => builder.match_resolution(resolution),
+        None => builder,
+    };
+    let builder = match matches.get_one::<Structure>("structure") {
+        Some(&structure) => {
+            let weight = *matches
+                .get_one::<f32>("structure_weight")
+                .expect("required");
+            builder.structure(structure, weight)
+        }
+        None => builder,
+    };
+    let builder = match matches.get_one::<u32>("min_distance") {
+        Some(&distance) => {
+            let metric = *matches
+                .get_one::<CellDistance>("cell_distance")
+                .expect("required");
+            builder.constraint(MinDistance::new(distance, metric))
+        }
+        None => builder,
+    };
+    let builder = match matches.get_one::<usize>("max_uses") {
+        Some(&max) => builder.constraint(MaxUses::new(max)),
+        None => builder,
+    };
+    let builder = match matches.get_one::<u64>("seed") {
+        Some(&seed) => builder.seed(seed),
+        None => builder,
+    };
+    let mosaic = builder.build()?;
+    let output = PathBuf::from(output);
+    mosaic.save(&output).map_err(|source| MosaicError::Save {
+        path: output,
+        source,
+    })
+}
+
+fn augment(matches: &ArgMatches) -> Augment {
+    let kinds = matches
+        .get_many::<String>("augment")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let has = |kind: &str| kinds.iter().any(|k| *k == kind);
+    Augment::new()
+        .flip_horizontal(has("hflip"))
+        .flip_vertical(has("vflip"))
+        .rotate(has("rotate"))
+        .crops(matches.get_one::<u32>("crops").copied().unwrap_or(0))
+}
+
+fn scan(matches: &ArgMatches) -> Scan {
+    let patterns = |id| matches.get_many::<String>(id).into_iter().flatten();
+    let scan = patterns("include").fold(Scan::new(), |scan, p| scan.include(p));
+    let scan = patterns("exclude").fold(scan, |scan, p| scan.exclude(p));
+    scan.follow_symlinks(matches.get_flag("follow_symlinks"))
+}
+
+fn index(matches: &ArgMatches) -> Result<(), MosaicError> {
+    let verbose = matches.get_flag("verbose");
+    let images = matches.get_one::<String>("images").expect("required");
+    let sizes = matches
+        .get_many::<(u32, u32)>("tile_size")
+        .expect("required")
+        .copied()
+        .collect::<Vec<_>>();
+    let color_spaces = matches
+        .get_many::<ColorSpace>("color_space")
+        .expect("required")
+        .copied()
+        .collect::<Vec<_>>();
+    let tile_fits = matches
+        .get_many::<TileFit>("tile_fit")
+        .expect("required")
+        .copied()
+        .collect::<Vec<_>>();
+    let summary = update_index(
+        images.as_ref(),
+        &scan(matches),
+        &color_spaces,
+        &tile_fits,
+        &sizes,
+        &Console::new(verbose),
+    )?;
+    println!(
+        "{} added, {} updated, {} removed, {} unchanged, {} skipped.",
+        summary.added, summary.updated, summary.removed, summary.unchanged, summary.skipped
     );
+    Ok(())
 }
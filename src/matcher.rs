@@ -0,0 +1,346 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use clap::{builder::PossibleValue, ValueEnum};
+use rayon::prelude::*;
+
+use crate::{
+    features::Features,
+    metric::Scorer,
+    mosaic::{similarity, Tile},
+};
+
+/// Side length of the downsampled grid stored in a descriptor.
+const DESCRIPTOR_GRID: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum Search {
+    #[default]
+    Exact,
+    Approximate,
+}
+
+impl ValueEnum for Search {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Search::Exact, Search::Approximate]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Search::Exact => {
+                PossibleValue::new("exact").help("Compare every block with every tile.")
+            }
+            Search::Approximate => PossibleValue::new("approximate")
+                .help("Shortlist tiles with a nearest-neighbour search before comparing them."),
+        })
+    }
+}
+
+impl std::fmt::Display for Search {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+pub(crate) struct Matcher {
+    scorer: Scorer,
+    shortlist: Option<(VpTree, usize)>,
+}
+
+impl Matcher {
+    pub(crate) fn new(search: Search, tiles: &[Tile], candidates: usize, scorer: Scorer) -> Self {
+        let shortlist = match search {
+            Search::Exact => None,
+            Search::Approximate => Some((
+                VpTree::new(tiles.par_iter().map(point).collect()),
+                candidates.max(1),
+            )),
+        };
+        Self { scorer, shortlist }
+    }
+
+    /// Returns the index of the allowed tile most similar to `block`.
+    pub(crate) fn best(
+        &self,
+        block: &Tile,
+        tiles: &[Tile],
+        allowed: impl Fn(usize) -> bool + Sync,
+    ) -> Option<usize> {
+        let score = |i: usize| similarity(block, &tiles[i], &self.scorer).map(|s| (s, i));
+        let best = match &self.shortlist {
+            None => (0..tiles.len())
+                .into_par_iter()
+                .filter(|&i| allowed(i))
+                .filter_map(score)
+                .min_by(|a, b| a.0.total_cmp(&b.0)),
+            Some((tree, candidates)) => tree
+                .nearest(&point(block), *candidates, allowed)
+                .into_iter()
+                .filter_map(score)
+                .min_by(|a, b| a.0.total_cmp(&b.0)),
+        };
+        best.map(|(_, i)| i)
+    }
+}
+
+/// Where `tile` sits in the search tree: its histogram when tiles are
+/// compared by histogram, and otherwise a descriptor of its features.
+fn point(tile: &Tile) -> Vec<f32> {
+    match &tile.histogram {
+        Some(histogram) => histogram.clone(),
+        None => describe(&tile.features),
+    }
+}
+
+/// Reduces features to their mean followed by the means of a
+/// `DESCRIPTOR_GRID` x `DESCRIPTOR_GRID` grid of cells.
+pub(crate) fn describe(features: &Features) -> Vec<f32> {
+    let width = features.width();
+    let height = features.height();
+    let channels = features.channels();
+    let cell = |i: usize, len: usize| {
+        let start = i * len / DESCRIPTOR_GRID;
+        start..((i + 1) * len / DESCRIPTOR_GRID).max(start + 1).min(len)
+    };
+    let mean = |xs: std::ops::Range<usize>, ys: std::ops::Range<usize>| {
+        let mut sum = vec![0.0; channels];
+        let mut count = 0.0;
+        for x in xs {
+            for y in ys.clone() {
+                for (s, v) in sum.iter_mut().zip(features.pixel(x, y)) {
+                    *s += v;
+                }
+                count += 1.0;
+            }
+        }
+        sum.into_iter()
+            .map(move |s| if count > 0.0 { s / count } else { 0.0 })
+    };
+    let mut descriptor = mean(0..width, 0..height).collect::<Vec<_>>();
+    for gx in 0..DESCRIPTOR_GRID {
+        for gy in 0..DESCRIPTOR_GRID {
+            descriptor.extend(mean(cell(gx, width), cell(gy, height)));
+        }
+    }
+    descriptor
+}
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+struct Node {
+    point: usize,
+    threshold: f32,
+    inside: Option<usize>,
+    outside: Option<usize>,
+}
+
+/// Vantage-point tree over descriptors, answering k-nearest-neighbour queries.
+pub(crate) struct VpTree {
+    points: Vec<Vec<f32>>,
+    nodes: Vec<Node>,
+    root: Option<usize>,
+}
+
+#[derive(PartialEq)]
+struct Candidate {
+    distance: f32,
+    point: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance)
+    }
+}
+
+impl VpTree {
+    pub(crate) fn new(points: Vec<Vec<f32>>) -> Self {
+        let mut tree = Self {
+            points,
+            nodes: vec![],
+            root: None,
+        };
+        let mut items = (0..tree.points.len()).collect::<Vec<_>>();
+        tree.root = tree.build(&mut items);
+        tree
+    }
+
+    fn build(&mut self, items: &mut [usize]) -> Option<usize> {
+        let (&mut vantage, rest) = items.split_first_mut()?;
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            point: vantage,
+            threshold: 0.0,
+            inside: None,
+            outside: None,
+        });
+        if rest.is_empty() {
+            return Some(node);
+        }
+        let points = &self.points;
+        let mid = rest.len() / 2;
+        rest.select_nth_unstable_by(mid, |&a, &b| {
+            distance(&points[vantage], &points[a])
+                .total_cmp(&distance(&points[vantage], &points[b]))
+        });
+        let threshold = distance(&points[vantage], &points[rest[mid]]);
+        let (inside, outside) = rest.split_at_mut(mid);
+        let inside = self.build(inside);
+        let outside = self.build(outside);
+        self.nodes[node] = Node {
+            point: vantage,
+            threshold,
+            inside,
+            outside,
+        };
+        Some(node)
+    }
+
+    /// Returns up to `k` allowed points closest to `query`, nearest first.
+    pub(crate) fn nearest(
+        &self,
+        query: &[f32],
+        k: usize,
+        allowed: impl Fn(usize) -> bool,
+    ) -> Vec<usize> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        self.search(self.root, query, k, &allowed, &mut heap);
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|c| c.point)
+            .collect()
+    }
+
+    fn search(
+        &self,
+        node: Option<usize>,
+        query: &[f32],
+        k: usize,
+        allowed: &impl Fn(usize) -> bool,
+        heap: &mut BinaryHeap<Candidate>,
+    ) {
+        let Some(node) = node.map(|n| &self.nodes[n]) else {
+            return;
+        };
+        let d = distance(query, &self.points[node.point]);
+        let tau = |heap: &BinaryHeap<Candidate>| match heap.peek() {
+            Some(c) if heap.len() == k => c.distance,
+            _ => f32::INFINITY,
+        };
+        if allowed(node.point) && d < tau(heap) {
+            heap.push(Candidate {
+                distance: d,
+                point: node.point,
+            });
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+        let (near, far) = if d < node.threshold {
+            (node.inside, node.outside)
+        } else {
+            (node.outside, node.inside)
+        };
+        self.search(near, query, k, allowed, heap);
+        if (d - node.threshold).abs() <= tau(heap) {
+            self.search(far, query, k, allowed, heap);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, Rgb32FImage};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{descriptor::histogram, metric::DistanceMetric, mosaic::ColorSpace};
+
+    fn brute_force(
+        points: &[Vec<f32>],
+        query: &[f32],
+        k: usize,
+        allowed: impl Fn(usize) -> bool,
+    ) -> Vec<usize> {
+        let mut order = (0..points.len())
+            .filter(|&i| allowed(i))
+            .collect::<Vec<_>>();
+        order.sort_by(|&a, &b| distance(query, &points[a]).total_cmp(&distance(query, &points[b])));
+        order.truncate(k);
+        order
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut random_point = || (0..5).map(|_| rng.gen::<f32>()).collect::<Vec<_>>();
+        let points = (0..300).map(|_| random_point()).collect::<Vec<_>>();
+        let tree = VpTree::new(points.clone());
+        for _ in 0..50 {
+            let query = random_point();
+            for k in [1, 5, 16] {
+                assert_eq!(
+                    tree.nearest(&query, k, |_| true),
+                    brute_force(&points, &query, k, |_| true)
+                );
+                let rare = |i: usize| i % 11 == 3;
+                assert_eq!(
+                    tree.nearest(&query, k, rare),
+                    brute_force(&points, &query, k, rare)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_returns_fewer_when_few_are_allowed() {
+        let points = (0..20).map(|i| vec![i as f32]).collect::<Vec<_>>();
+        let tree = VpTree::new(points);
+        assert_eq!(tree.nearest(&[7.2], 4, |i| i == 2 || i == 15), vec![2, 15]);
+        assert!(tree.nearest(&[7.2], 4, |_| false).is_empty());
+        assert!(VpTree::new(vec![]).nearest(&[0.0], 4, |_| true).is_empty());
+    }
+
+    fn histogram_tile(image: Rgb32FImage) -> Tile {
+        Tile {
+            features: ColorSpace::Rgb.features(&image),
+            histogram: Some(histogram(&image)),
+            shape: None,
+            image,
+        }
+    }
+
+    #[test]
+    fn approximate_search_compares_histograms() {
+        let (red, blue) = (Rgb([1.0, 0.0, 0.0]), Rgb([0.0, 0.0, 1.0]));
+        let checkerboard =
+            Rgb32FImage::from_fn(8, 8, |x, y| if (x + y) % 2 == 0 { red } else { blue });
+        let halves = Rgb32FImage::from_fn(8, 8, |x, _| if x < 4 { red } else { blue });
+        let purple = Rgb32FImage::from_pixel(8, 8, Rgb([0.5, 0.0, 0.5]));
+        // The purple tile looks the same as the block once averaged, but only
+        // the halves have the same colors.
+        let block = histogram_tile(checkerboard);
+        let tiles = [histogram_tile(purple), histogram_tile(halves)];
+        let scorer = Scorer::new(DistanceMetric::Cie76, ColorSpace::Rgb, [1.0; 3], 1.0);
+        for search in [Search::Exact, Search::Approximate] {
+            let matcher = Matcher::new(search, &tiles, 1, scorer);
+            assert_eq!(matcher.best(&block, &tiles, |_| true), Some(1), "{search}");
+        }
+    }
+}
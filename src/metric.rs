@@ -0,0 +1,363 @@
+use clap::{builder::PossibleValue, ValueEnum};
+
+use crate::{
+    features::Features,
+    mosaic::ColorSpace,
+    structure::{Shape, Structure},
+};
+
+/// How the difference between two pixels is measured.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum DistanceMetric {
+    /// Euclidean distance, which is ΔE*76 in L*a*b*.
+    #[default]
+    Cie76,
+    /// ΔE*94 with the graphic arts constants.
+    Cie94,
+    /// ΔE*00.
+    Ciede2000,
+}
+
+impl ValueEnum for DistanceMetric {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            DistanceMetric::Cie76,
+            DistanceMetric::Cie94,
+            DistanceMetric::Ciede2000,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            DistanceMetric::Cie76 => PossibleValue::new("cie76")
+                .alias("euclidean")
+                .help("Euclidean distance. Works with every color space."),
+            DistanceMetric::Cie94 => PossibleValue::new("cie94")
+                .help("CIE94 color difference. Requires the lab color space."),
+            DistanceMetric::Ciede2000 => PossibleValue::new("ciede2000")
+                .help("CIEDE2000 color difference. Requires the lab color space."),
+        })
+    }
+}
+
+impl std::fmt::Display for DistanceMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl DistanceMetric {
+    /// Whether the metric is only defined for L*a*b* pixels.
+    pub fn requires_lab(self) -> bool {
+        self != DistanceMetric::Cie76
+    }
+
+    /// Distance from the reference pixel `a` to `b`, with the terms of the
+    /// metric scaled by `weights`.
+    pub(crate) fn distance(self, a: &[f32], b: &[f32], weights: &[f32; 3]) -> f32 {
+        match self {
+            DistanceMetric::Cie76 => cie76(a, b, weights),
+            DistanceMetric::Cie94 => cie94(a, b, weights),
+            DistanceMetric::Ciede2000 => ciede2000(a, b, weights),
+        }
+    }
+}
+
+/// Compares pixels of one color space with one metric.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Scorer {
+    metric: DistanceMetric,
+    /// Whether the first channel is a hue in 0..1 that wraps around.
+    circular_hue: bool,
+    weights: [f32; 3],
+    /// Structural comparison mixed with the color distance, and its weight.
+    structure: Option<(Structure, f32)>,
+}
+
+impl Scorer {
+    /// `weights` apply to the channels of `color_space`, or to the lightness,
+    /// chroma and hue terms of CIE94 and CIEDE2000. `chroma_weight` further
+    /// scales Cb and Cr in `ColorSpace::YCbCr`.
+    pub(crate) fn new(
+        metric: DistanceMetric,
+        color_space: ColorSpace,
+        weights: [f32; 3],
+        chroma_weight: f32,
+    ) -> Self {
+        let [w0, w1, w2] = weights;
+        Self {
+            metric,
+            circular_hue: matches!(color_space, ColorSpace::Hsv | ColorSpace::Hsl),
+            weights: if color_space == ColorSpace::YCbCr {
+                [w0, w1 * chroma_weight, w2 * chroma_weight]
+            } else {
+                weights
+            },
+            structure: None,
+        }
+    }
+
+    pub(crate) fn with_structure(mut self, structure: Option<(Structure, f32)>) -> Self {
+        self.structure = structure;
+        self
+    }
+
+    pub(crate) fn structure(&self) -> Option<Structure> {
+        self.structure.map(|(structure, _)| structure)
+    }
+
+    /// Combines the color distance of a block and a tile with the difference
+    /// of their shapes as a weighted geometric mean, which keeps the ranking
+    /// independent of the units of either.
+    pub(crate) fn mix(&self, color: f32, a: Option<&Shape>, b: Option<&Shape>) -> f32 {
+        match (self.structure, a, b) {
+            (Some((structure, weight)), Some(a), Some(b)) => {
+                let shape = structure.distance(a, b);
+                (color + f32::EPSILON).powf(1.0 - weight) * (shape + f32::EPSILON).powf(weight)
+            }
+            _ => color,
+        }
+    }
+
+    /// Sum of the distances between the pixels of `a` and `b`, which must be
+    /// comparable.
+    pub(crate) fn distance(&self, a: &Features, b: &Features) -> f32 {
+        let channels = a.channels();
+        let (a, b) = (a.as_slice(), b.as_slice());
+        match channels {
+            3 if self.metric == DistanceMetric::Cie76 && !self.circular_hue => {
+                let [w0, w1, w2] = self.weights;
+                let (a, _) = a.as_chunks::<3>();
+                let (b, _) = b.as_chunks::<3>();
+                a.iter()
+                    .zip(b)
+                    .map(|(a, b)| {
+                        let d0 = w0 * (a[0] - b[0]);
+                        let d1 = w1 * (a[1] - b[1]);
+                        let d2 = w2 * (a[2] - b[2]);
+                        (d0 * d0 + d1 * d1 + d2 * d2).sqrt()
+                    })
+                    .sum()
+            }
+            1 => {
+                let w0 = self.weights[0];
+                a.iter().zip(b).map(|(a, b)| w0 * (a - b).abs()).sum()
+            }
+            0 => 0.0,
+            _ => a
+                .chunks_exact(channels)
+                .zip(b.chunks_exact(channels))
+                .map(|(a, b)| self.pixel(a, b))
+                .sum(),
+        }
+    }
+
+    pub(crate) fn pixel(&self, a: &[f32], b: &[f32]) -> f32 {
+        if !self.circular_hue {
+            return self.metric.distance(a, b, &self.weights);
+        }
+        let d = (a[0] - b[0]).abs();
+        let hue = d.min(1.0 - d);
+        (self.weights[0] * hue).hypot(cie76(
+            &a[1..],
+            &b[1..],
+            &[self.weights[1], self.weights[2], 0.0],
+        ))
+    }
+}
+
+fn cie76(a: &[f32], b: &[f32], weights: &[f32; 3]) -> f32 {
+    a.iter()
+        .zip(b)
+        .zip(weights)
+        .map(|((a, b), w)| (w * (a - b)).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// https://en.wikipedia.org/wiki/Color_difference#CIE94
+fn cie94(lab1: &[f32], lab2: &[f32], weights: &[f32; 3]) -> f32 {
+    let (k_l, k1, k2) = (1.0, 0.045, 0.015);
+    let c1 = lab1[1].hypot(lab1[2]);
+    let c2 = lab2[1].hypot(lab2[2]);
+    let dl = lab1[0] - lab2[0];
+    let dc = c1 - c2;
+    let da = lab1[1] - lab2[1];
+    let db = lab1[2] - lab2[2];
+    let dh2 = (da * da + db * db - dc * dc).max(0.0);
+    let s_c = 1.0 + k1 * c1;
+    let s_h = 1.0 + k2 * c1;
+    let [w_l, w_c, w_h] = *weights;
+    ((w_l * dl / k_l).powi(2) + (w_c * dc / s_c).powi(2) + w_h * w_h * dh2 / (s_h * s_h)).sqrt()
+}
+
+/// Sharma, Wu and Dalal, "The CIEDE2000 Color-Difference Formula:
+/// Implementation Notes, Supplementary Test Data, and Mathematical Observations".
+fn ciede2000(lab1: &[f32], lab2: &[f32], weights: &[f32; 3]) -> f32 {
+    let [l1, a1, b1] = [lab1[0], lab1[1], lab1[2]].map(f64::from);
+    let [l2, a2, b2] = [lab2[0], lab2[1], lab2[2]].map(f64::from);
+    let pow25_7 = 25f64.powi(7);
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + pow25_7)).sqrt());
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+    let c1p = a1p.hypot(b1);
+    let c2p = a2p.hypot(b2);
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let dlp = l2 - l1;
+    let dcp = c2p - c1p;
+    let dhp = if c1p * c2p == 0.0 {
+        0.0
+    } else if (h2p - h1p).abs() <= 180.0 {
+        h2p - h1p
+    } else if h2p - h1p > 180.0 {
+        h2p - h1p - 360.0
+    } else {
+        h2p - h1p + 360.0
+    };
+    let dhp = 2.0 * (c1p * c2p).sqrt() * (dhp / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+    let h_bar_p = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+    let t = 1.0 - 0.17 * (h_bar_p - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar_p).to_radians().cos()
+        + 0.32 * (3.0 * h_bar_p + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar_p - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((h_bar_p - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_bar_p.powi(7) / (c_bar_p.powi(7) + pow25_7)).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    let [w_l, w_c, w_h] = weights.map(f64::from);
+    let l = w_l * dlp / s_l;
+    let c = w_c * dcp / s_c;
+    let h = w_h * dhp / s_h;
+    (l * l + c * c + h * h + r_t * c * h).sqrt() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNWEIGHTED: [f32; 3] = [1.0; 3];
+
+    /// The test data of Sharma, Wu and Dalal: two L*a*b* colors and ΔE*00.
+    #[rustfmt::skip]
+    const SHARMA: [([f32; 3], [f32; 3], f32); 34] = [
+        ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+        ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+        ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+        ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -1.1848, -84.8006], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -0.9009, -85.5211], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+        ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0009], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0010], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0011], 7.2195),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0012], 7.2195),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0009, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0010, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0011, -2.4900], 4.7461),
+        ([50.0, 2.5000, 0.0000], [50.0, 0.0000, -2.5000], 4.3065),
+        ([50.0, 2.5000, 0.0000], [73.0, 25.0000, -18.0000], 27.1492),
+        ([50.0, 2.5000, 0.0000], [61.0, -5.0000, 29.0000], 22.8977),
+        ([50.0, 2.5000, 0.0000], [56.0, -27.0000, -3.0000], 31.9030),
+        ([50.0, 2.5000, 0.0000], [58.0, 24.0000, 15.0000], 19.4535),
+        ([50.0, 2.5000, 0.0000], [50.0, 3.1736, 0.5854], 1.0000),
+        ([50.0, 2.5000, 0.0000], [50.0, 3.2972, 0.0000], 1.0000),
+        ([50.0, 2.5000, 0.0000], [50.0, 1.8634, 0.5757], 1.0000),
+        ([50.0, 2.5000, 0.0000], [50.0, 3.2592, 0.3350], 1.0000),
+        ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+        ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+        ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+        ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+        ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+        ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+        ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+        ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+        ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+
+    #[test]
+    fn ciede2000_matches_sharma_test_data() {
+        for (i, (lab1, lab2, expected)) in SHARMA.iter().enumerate() {
+            for (a, b) in [(lab1, lab2), (lab2, lab1)] {
+                let actual = ciede2000(a, b, &UNWEIGHTED);
+                assert!(
+                    (actual - expected).abs() < 1e-4,
+                    "pair {}: {actual} instead of {expected}",
+                    i + 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cie94_matches_reference_values() {
+        #[rustfmt::skip]
+        let cases = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 1.3950),
+            ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 34.6892),
+            ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.3910),
+            ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4195),
+            ([40.0, 10.0, -20.0], [40.0, 10.0, -20.0], 0.0),
+            ([40.0, 10.0, -20.0], [47.5, 10.0, -20.0], 7.5),
+        ];
+        for (lab1, lab2, expected) in cases {
+            let actual = cie94(&lab1, &lab2, &UNWEIGHTED);
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "{actual} instead of {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn cie94_scales_by_the_reference_chroma() {
+        let gray = [50.0, 0.0, 0.0];
+        let red = [50.0, 40.0, 0.0];
+        assert!((cie94(&gray, &red, &UNWEIGHTED) - 40.0).abs() < 1e-4);
+        assert!((cie94(&red, &gray, &UNWEIGHTED) - 40.0 / 2.8).abs() < 1e-4);
+    }
+
+    #[test]
+    fn weights_scale_each_term() {
+        let a = [50.0, 0.0, 0.0];
+        let b = [53.0, 0.0, 0.0];
+        for metric in [
+            DistanceMetric::Cie76,
+            DistanceMetric::Cie94,
+            DistanceMetric::Ciede2000,
+        ] {
+            let unweighted = metric.distance(&a, &b, &UNWEIGHTED);
+            let weighted = metric.distance(&a, &b, &[2.0, 1.0, 1.0]);
+            assert!((weighted - 2.0 * unweighted).abs() < 1e-4, "{metric}");
+            assert_eq!(metric.distance(&a, &b, &[0.0, 1.0, 1.0]), 0.0, "{metric}");
+        }
+    }
+}
@@ -1,28 +1,63 @@
-use std::{collections::BTreeSet, fs, path::Path};
+use std::{
+    collections::{hash_map, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::Mutex,
+};
 
-use anyhow::Result;
 use clap::{builder::PossibleValue, ValueEnum};
 use image::{
+    buffer::ConvertBuffer,
     imageops::{crop_imm, replace, resize, FilterType::Lanczos3},
-    DynamicImage, ImageReader, Luma, Pixel, Rgb, Rgb32FImage,
+    DynamicImage, GrayImage, ImageBuffer, ImageReader, Luma, Pixel, Rgb, Rgb32FImage, RgbImage,
 };
-use indicatif::ProgressBar;
-use itertools::{iproduct, Itertools};
-use rand::{seq::SliceRandom, thread_rng};
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, Rng, SeedableRng};
 use rayon::prelude::*;
 
-use crate::lab::{Lab, PixelLabExt};
+use crate::{
+    assignment::{hungarian, within_limits, Assignment},
+    augment::{Augment, Variant},
+    blend::{blend, BlendMode},
+    color::{rgb2hsl, rgb2hsv, rgb2luv, rgb2oklab, rgb2ycbcr},
+    descriptor::{histogram_distance, Descriptor},
+    error::MosaicError,
+    features::Features,
+    index::TileIndex,
+    lab::PixelLabExt,
+    layout::{extend, Fit, Layout, Rectangular, Region},
+    matcher::{Matcher, Search},
+    metric::{DistanceMetric, Scorer},
+    progress::{Progress, Silent, Stage},
+    scan::Scan,
+    source::{self, Origin, TileSource},
+    structure::{Shape, Structure},
+    tile_fit::TileFit,
+};
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub(crate) enum ColorSpace {
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ColorSpace {
     Rgb,
     Lab,
     Gray,
+    Oklab,
+    Hsv,
+    Hsl,
+    YCbCr,
+    Luv,
 }
 
 impl ValueEnum for ColorSpace {
     fn value_variants<'a>() -> &'a [Self] {
-        &[ColorSpace::Rgb, ColorSpace::Lab, ColorSpace::Gray]
+        &[
+            ColorSpace::Rgb,
+            ColorSpace::Lab,
+            ColorSpace::Gray,
+            ColorSpace::Oklab,
+            ColorSpace::Hsv,
+            ColorSpace::Hsl,
+            ColorSpace::YCbCr,
+            ColorSpace::Luv,
+        ]
     }
 
     fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
@@ -34,6 +69,16 @@ impl ValueEnum for ColorSpace {
                 .help("Use L*a*b* color space for more perceptually uniform matching."),
             ColorSpace::Gray => PossibleValue::new("gray")
                 .help("Use grayscale for matching tiles based on intensity."),
+            ColorSpace::Oklab => PossibleValue::new("oklab")
+                .help("Use Oklab color space for more uniform hue than L*a*b*."),
+            ColorSpace::Hsv => PossibleValue::new("hsv")
+                .help("Use HSV color space, comparing hues around the color wheel."),
+            ColorSpace::Hsl => PossibleValue::new("hsl")
+                .help("Use HSL color space, comparing hues around the color wheel."),
+            ColorSpace::YCbCr => PossibleValue::new("ycbcr")
+                .help("Use Y'CbCr color space, with chroma weighted by --chroma-weight."),
+            ColorSpace::Luv => PossibleValue::new("luv")
+                .help("Use L*u*v* color space for perceptually uniform matching."),
         })
     }
 }
@@ -47,141 +92,1258 @@ impl std::fmt::Display for ColorSpace {
     }
 }
 
-pub(crate) fn mosaic(
-    target: &Path,
-    row_size: u32,
-    col_size: u32,
-    images: &Path,
-    output: &Path,
+impl ColorSpace {
+    pub(crate) fn features(self, image: &Rgb32FImage) -> Features {
+        match self {
+            ColorSpace::Rgb => Features::from_image(image, |&Rgb(rgb)| rgb),
+            ColorSpace::Lab => Features::from_image(image, |p| p.to_lab().0),
+            ColorSpace::Gray => Features::from_image(image, |p| p.to_luma().0),
+            ColorSpace::Oklab => Features::from_image(image, |Rgb(rgb)| rgb2oklab(rgb)),
+            ColorSpace::Hsv => Features::from_image(image, |Rgb(rgb)| rgb2hsv(rgb)),
+            ColorSpace::Hsl => Features::from_image(image, |Rgb(rgb)| rgb2hsl(rgb)),
+            ColorSpace::YCbCr => Features::from_image(image, |Rgb(rgb)| rgb2ycbcr(rgb)),
+            ColorSpace::Luv => Features::from_image(image, |Rgb(rgb)| rgb2luv(rgb)),
+        }
+    }
+}
+
+pub(crate) struct Tile {
+    pub(crate) image: Rgb32FImage,
+    pub(crate) features: Features,
+    /// Only computed when a `Structure` is compared.
+    pub(crate) shape: Option<Shape>,
+    /// Only computed for `Descriptor::Histogram`.
+    pub(crate) histogram: Option<Vec<f32>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DuplicatePolicy {
+    #[default]
+    Allow,
+    Avoid,
+}
+
+/// Restricts where tiles may be placed during greedy assignment.
+///
+/// Cells are the `Region::cell` coordinates given by the `Layout`, which are
+/// `(x, y)` grid coordinates for `Rectangular`, and tiles are indices of the
+/// source images, shared by the variants made with `Augment`. A tile is only
+/// considered for a cell if every constraint allows it.
+pub trait PlacementConstraint: Send + Sync {
+    fn allows(&self, tile: usize, cell: (u32, u32)) -> bool;
+
+    fn place(&mut self, tile: usize, cell: (u32, u32));
+
+    /// Names the constraint when no tile satisfies it, as in "No tile can be
+    /// placed without breaking {description}."
+    fn describe(&self) -> String {
+        "a placement constraint".to_string()
+    }
+}
+
+/// Forbids reuse until every tile has been placed once, then starts over.
+struct AvoidDuplicates {
+    used: BTreeSet<usize>,
+    tiles: usize,
+}
+
+impl PlacementConstraint for AvoidDuplicates {
+    fn allows(&self, tile: usize, _cell: (u32, u32)) -> bool {
+        !self.used.contains(&tile)
+    }
+
+    fn place(&mut self, tile: usize, _cell: (u32, u32)) {
+        self.used.insert(tile);
+        if self.used.len() == self.tiles {
+            self.used.clear();
+        }
+    }
+
+    fn describe(&self) -> String {
+        "the rule against duplicates".to_string()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum CellDistance {
+    #[default]
+    Chebyshev,
+    Euclidean,
+}
+
+impl ValueEnum for CellDistance {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[CellDistance::Chebyshev, CellDistance::Euclidean]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            CellDistance::Chebyshev => {
+                PossibleValue::new("chebyshev").help("Count diagonal neighbours as one cell away.")
+            }
+            CellDistance::Euclidean => {
+                PossibleValue::new("euclidean").help("Use the straight-line distance.")
+            }
+        })
+    }
+}
+
+impl CellDistance {
+    fn between(self, a: (u32, u32), b: (u32, u32)) -> f32 {
+        let dx = a.0.abs_diff(b.0) as f32;
+        let dy = a.1.abs_diff(b.1) as f32;
+        match self {
+            CellDistance::Chebyshev => dx.max(dy),
+            CellDistance::Euclidean => dx.hypot(dy),
+        }
+    }
+}
+
+/// Allows reuse of a tile, but never within `distance` cells of another copy.
+pub struct MinDistance {
+    distance: f32,
+    metric: CellDistance,
+    placed: HashMap<usize, Vec<(u32, u32)>>,
+}
+
+impl MinDistance {
+    pub fn new(distance: u32, metric: CellDistance) -> Self {
+        Self {
+            distance: distance as f32,
+            metric,
+            placed: HashMap::new(),
+        }
+    }
+}
+
+impl PlacementConstraint for MinDistance {
+    fn allows(&self, tile: usize, cell: (u32, u32)) -> bool {
+        self.placed.get(&tile).is_none_or(|cells| {
+            cells
+                .iter()
+                .all(|&other| self.metric.between(cell, other) > self.distance)
+        })
+    }
+
+    fn place(&mut self, tile: usize, cell: (u32, u32)) {
+        self.placed.entry(tile).or_default().push(cell);
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "the minimum distance of {} cells between copies of a tile",
+            self.distance
+        )
+    }
+}
+
+/// Allows each tile to be placed at most `max` times.
+pub struct MaxUses {
+    max: usize,
+    uses: HashMap<usize, usize>,
+}
+
+impl MaxUses {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            uses: HashMap::new(),
+        }
+    }
+}
+
+impl PlacementConstraint for MaxUses {
+    fn allows(&self, tile: usize, _cell: (u32, u32)) -> bool {
+        self.uses.get(&tile).copied().unwrap_or(0) < self.max
+    }
+
+    fn place(&mut self, tile: usize, _cell: (u32, u32)) {
+        *self.uses.entry(tile).or_default() += 1;
+    }
+
+    fn describe(&self) -> String {
+        format!("the limit of {} uses per tile", self.max)
+    }
+}
+
+enum Target {
+    Path(PathBuf),
+    Image(DynamicImage),
+}
+
+/// The resolution the mosaic is rendered at, if not the target's.
+#[derive(Clone, Copy, Debug)]
+enum RenderSize {
+    Tile(u32, u32),
+    Width(u32),
+}
+
+/// Maps the canvas onto the rendered mosaic along one axis, `to / from` times
+/// larger. Pixels are mapped by their centres, so the regions of a layout
+/// cover the rendered mosaic exactly as they cover the canvas.
+#[derive(Clone, Copy, Debug)]
+struct Scale {
+    to: i128,
+    from: i128,
+}
+
+impl Scale {
+    fn new(to: u32, from: u32) -> Self {
+        Self {
+            to: to.into(),
+            from: from.into(),
+        }
+    }
+
+    /// The first rendered pixel whose centre lies at or past `x` on the canvas.
+    fn edge(self, x: i64) -> i64 {
+        let x = i128::from(x);
+        -(self.from - 2 * self.to * x).div_euclid(2 * self.from) as i64
+    }
+
+    /// The pixel of the canvas under the centre of the rendered pixel `x`.
+    fn source(self, x: i64) -> i64 {
+        let x = i128::from(x);
+        ((2 * x + 1) * self.from).div_euclid(2 * self.to) as i64
+    }
+}
+
+pub struct MosaicBuilder {
+    target: Option<Target>,
+    grid: Option<(u32, u32)>,
+    tiles: Option<TileSource>,
+    scan: Scan,
     color_space: ColorSpace,
-    avoid_duplicates: bool,
-) {
-    println!("[1/3] Preprocessing the target image.");
-    let target = ImageReader::open(target)
-        .expect("Failed to open the target image.")
-        .decode()
-        .expect("Failed to decode the target image.")
+    duplicates: DuplicatePolicy,
+    metric: DistanceMetric,
+    channel_weights: [f32; 3],
+    chroma_weight: f32,
+    structure: Option<(Structure, f32)>,
+    layout: Box<dyn Layout>,
+    fit: Fit,
+    tile_fit: TileFit,
+    augment: Augment,
+    render: Option<RenderSize>,
+    max_memory: Option<usize>,
+    descriptor: Descriptor,
+    match_resolution: Option<u32>,
+    search: Search,
+    candidates: usize,
+    assignment: Assignment,
+    constraints: Vec<Box<dyn PlacementConstraint>>,
+    blend: f32,
+    blend_mode: BlendMode,
+    seed: Option<u64>,
+    progress: Box<dyn Progress>,
+}
+
+impl Default for MosaicBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MosaicBuilder {
+    pub fn new() -> Self {
+        Self {
+            target: None,
+            grid: None,
+            tiles: None,
+            scan: Scan::default(),
+            color_space: ColorSpace::Lab,
+            duplicates: DuplicatePolicy::default(),
+            metric: DistanceMetric::default(),
+            channel_weights: [1.0; 3],
+            chroma_weight: 1.0,
+            structure: None,
+            layout: Box::new(Rectangular),
+            fit: Fit::default(),
+            tile_fit: TileFit::default(),
+            augment: Augment::default(),
+            render: None,
+            max_memory: None,
+            descriptor: Descriptor::default(),
+            match_resolution: None,
+            search: Search::default(),
+            candidates: 16,
+            assignment: Assignment::default(),
+            constraints: vec![],
+            blend: 0.0,
+            blend_mode: BlendMode::default(),
+            seed: None,
+            progress: Box::new(Silent),
+        }
+    }
+
+    pub fn target(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target = Some(Target::Path(path.into()));
+        self
+    }
+
+    pub fn target_image(mut self, image: DynamicImage) -> Self {
+        self.target = Some(Target::Image(image));
+        self
+    }
+
+    /// `row_size` tiles across and `col_size` tiles down.
+    pub fn grid(mut self, row_size: u32, col_size: u32) -> Self {
+        self.grid = Some((row_size, col_size));
+        self
+    }
+
+    pub fn tiles(mut self, source: TileSource) -> Self {
+        self.tiles = Some(source);
+        self
+    }
+
+    /// Which files are read from directories and archives.
+    /// Defaults to every file, without following symbolic links.
+    pub fn scan(mut self, scan: Scan) -> Self {
+        self.scan = scan;
+        self
+    }
+
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicates = policy;
+        self
+    }
+
+    /// Metrics other than `DistanceMetric::Cie76` require `ColorSpace::Lab`.
+    pub fn metric(mut self, metric: DistanceMetric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Weights of the three channels of the color space, such as L*, a* and b*,
+    /// when comparing pixels. `ColorSpace::Gray` only uses the first. With
+    /// `DistanceMetric::Cie94` or `DistanceMetric::Ciede2000` they weight the
+    /// lightness, chroma and hue differences instead.
+    pub fn channel_weights(mut self, weights: [f32; 3]) -> Self {
+        self.channel_weights = weights;
+        self
+    }
+
+    /// Weight of Cb and Cr relative to Y' when matching in `ColorSpace::YCbCr`.
+    pub fn chroma_weight(mut self, weight: f32) -> Self {
+        self.chroma_weight = weight;
+        self
+    }
+
+    /// Also compares the internal structure of blocks and tiles, so that
+    /// tiles whose edges follow those of the target are preferred. `weight`
+    /// between 0 and 1 sets how much the structure counts against the color.
+    pub fn structure(mut self, structure: Structure, weight: f32) -> Self {
+        self.structure = Some((structure, weight));
+        self
+    }
+
+    /// How the target is divided into tiles. Defaults to `Rectangular`.
+    pub fn layout(mut self, layout: impl Layout + 'static) -> Self {
+        self.layout = Box::new(layout);
+        self
+    }
+
+    /// How the target is scaled to the grid. Defaults to `Fit::Stretch`.
+    pub fn fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    /// How source images are resized to tiles with a different aspect ratio.
+    pub fn tile_fit(mut self, tile_fit: TileFit) -> Self {
+        self.tile_fit = tile_fit;
+        self
+    }
+
+    /// Makes extra tiles from every source image by flipping, rotating and
+    /// cropping it.
+    pub fn augment(mut self, augment: Augment) -> Self {
+        self.augment = augment;
+        self
+    }
+
+    /// Renders every tile at `width` x `height` pixels from the source images,
+    /// while matching still happens at the resolution of the target. The
+    /// mosaic is scaled by `width` and `height` over the size of a cell along
+    /// each axis, so regions that do not start on a whole multiple of it may
+    /// be a pixel larger or smaller.
+    pub fn render_tile_size(mut self, width: u32, height: u32) -> Self {
+        self.render = Some(RenderSize::Tile(width, height));
+        self
+    }
+
+    /// Renders the mosaic `width` pixels wide from the source images, like
+    /// `render_tile_size`, scaling both axes by the same factor.
+    pub fn output_width(mut self, width: u32) -> Self {
+        self.render = Some(RenderSize::Width(width));
+        self
+    }
+
+    /// Decodes no more source images at once than fit in about `bytes` of
+    /// memory, counting their pixels both as decoded and converted for
+    /// resizing. An image larger than that is still decoded, on its own.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn descriptor(mut self, descriptor: Descriptor) -> Self {
+        self.descriptor = descriptor;
+        self
+    }
+
+    /// Compares blocks and tiles downsampled to at most `resolution` pixels
+    /// per side instead of at full resolution, which is faster and less
+    /// sensitive to noise.
+    pub fn match_resolution(mut self, resolution: u32) -> Self {
+        self.match_resolution = Some(resolution.max(1));
+        self
+    }
+
+    pub fn search(mut self, search: Search) -> Self {
+        self.search = search;
+        self
+    }
+
+    /// Number of tiles shortlisted by `Search::Approximate` before the exact comparison.
+    pub fn candidates(mut self, candidates: usize) -> Self {
+        self.candidates = candidates;
+        self
+    }
+
+    /// `Assignment::Optimal` requires `DuplicatePolicy::Avoid`, and is refused
+    /// for grids and tile sets so large that it would take minutes.
+    pub fn assignment(mut self, assignment: Assignment) -> Self {
+        self.assignment = assignment;
+        self
+    }
+
+    /// Adds a constraint on where tiles may be placed. Constraints cannot be
+    /// combined with `Assignment::Optimal` when duplicates are avoided.
+    pub fn constraint(mut self, constraint: impl PlacementConstraint + 'static) -> Self {
+        self.constraints.push(Box::new(constraint));
+        self
+    }
+
+    /// Shifts each placed tile toward the colours of its block by `amount`,
+    /// from 0 (unchanged) to 1 (fully corrected).
+    pub fn blend(mut self, amount: f32, mode: BlendMode) -> Self {
+        self.blend = amount.clamp(0.0, 1.0);
+        self.blend_mode = mode;
+        self
+    }
+
+    /// Seeds every random choice, so the same inputs always produce the same mosaic.
+    /// Without a seed a random one is chosen and reported through `Progress::message`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn progress(mut self, progress: impl Progress + 'static) -> Self {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    pub fn build(self) -> Result<RgbImage, MosaicError> {
+        let target = self.target.ok_or(MosaicError::MissingTarget)?;
+        let tiles = self.tiles.ok_or(MosaicError::MissingTiles)?;
+        let (row_size, col_size) = self.grid.ok_or(MosaicError::InvalidGrid)?;
+        if row_size == 0 || col_size == 0 {
+            return Err(MosaicError::InvalidGrid);
+        }
+        let metric = self.metric;
+        if metric.requires_lab() && self.color_space != ColorSpace::Lab {
+            return Err(MosaicError::IncompatibleMetric {
+                metric,
+                color_space: self.color_space,
+            });
+        }
+        let avoid_duplicates = self.duplicates == DuplicatePolicy::Avoid;
+        if self.assignment == Assignment::Optimal {
+            if !avoid_duplicates {
+                return Err(MosaicError::OptimalWithDuplicates);
+            }
+            if !self.constraints.is_empty() {
+                return Err(MosaicError::ConstraintsWithOptimal);
+            }
+        }
+        let scorer = Scorer::new(
+            metric,
+            self.color_space,
+            self.channel_weights,
+            self.chroma_weight,
+        )
+        .with_structure(self.structure);
+        let progress = self.progress.as_ref();
+        let seed = self.seed.unwrap_or_else(|| thread_rng().gen());
+        progress.message(&format!("Seed: {seed}."));
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        progress.start(Stage::Target, 1);
+        let target = match target {
+            Target::Path(path) => ImageReader::open(&path)
+                .map_err(|source| MosaicError::TargetRead {
+                    path: path.clone(),
+                    source,
+                })?
+                .decode()
+                .map_err(|source| MosaicError::TargetDecode { path, source })?,
+            Target::Image(image) => image,
+        }
         .into_rgb32f();
-    let width = target.width() / row_size;
-    let height = target.height() / col_size;
-    // いろ空間の変更
-    let mut target = resize(&target, width * row_size, height * col_size, Lanczos3);
-    println!("[1/3] Finished preprocessing the target image.");
-
-    println!("[2/3] Preprocessing the source images.");
-    let images =
-        read_images_from_directory(images).expect("Failed to read images from the directory.");
-    let color_space = match color_space {
-        ColorSpace::Rgb => rgb_identity,
-        ColorSpace::Lab => rgb2lab,
-        ColorSpace::Gray => rgb2gray,
-    };
-    let pb = ProgressBar::new(images.len() as u64);
-    let images = images
-        .par_iter()
-        .map(|img| {
-            pb.inc(1);
-            let img = resize(img, width, height, Lanczos3);
-            let col = color_space(&img);
-            (img, col)
-        })
-        .collect::<Vec<_>>();
-    let mut used = BTreeSet::new();
-    pb.finish_and_clear();
-    println!("[2/3] Finished preprocessing the source images.");
-
-    println!("[3/3] Generating the mosaic image.");
-    let mut rng = thread_rng();
-    let mut block_index = iproduct!(0..col_size, 0..row_size).collect_vec();
-    block_index.shuffle(&mut rng);
-    let pb = ProgressBar::new(block_index.len() as u64);
-    for (y, x) in block_index {
-        if avoid_duplicates && used.len() == images.len() {
-            used.clear();
-        }
-        let block = crop_imm(&target, x * width, y * height, width, height);
-        let block_image = block.to_image();
-        let (_score, idx, best) = images
-            .par_iter()
-            .enumerate()
-            .filter_map(|(i, (img, col))| {
-                if avoid_duplicates && used.contains(&i) {
-                    return None;
-                }
-                let block_col = color_space(&block_image);
-                similarity(&block_col, col).map(|s| (s, i, img))
+        let width = target.width() / row_size;
+        let height = target.height() / col_size;
+        if width == 0 || height == 0 {
+            return Err(MosaicError::GridLargerThanTarget {
+                row_size,
+                col_size,
+                width: target.width(),
+                height: target.height(),
+            });
+        }
+        let (width, height) = match self.layout.cell_size(width, height) {
+            (0, _) | (_, 0) => return Err(MosaicError::CellTooSmall { width, height }),
+            size => size,
+        };
+        progress.message(&format!(
+            "Fitted the {}x{} target to {}x{} with {}.",
+            target.width(),
+            target.height(),
+            width * row_size,
+            height * col_size,
+            self.fit
+        ));
+        // いろ空間の変更
+        let target = self.fit.apply(&target, width * row_size, height * col_size);
+        let regions = self.layout.regions(&target, width, height);
+        // Regions past the edges are matched against a canvas extended with the
+        // nearest edge pixels, which is cropped away at the end.
+        let (target_width, target_height) = target.dimensions();
+        let margin = |overhang: fn(&Region, u32, u32) -> i64| {
+            regions
+                .iter()
+                .map(|r| overhang(r, target_width, target_height))
+                .max()
+                .unwrap_or(0)
+                .max(0) as u32
+        };
+        let left = margin(|r, _, _| -r.x);
+        let top = margin(|r, _, _| -r.y);
+        let right = margin(|r, w, _| r.x + i64::from(r.width) - i64::from(w));
+        let bottom = margin(|r, _, h| r.y + i64::from(r.height) - i64::from(h));
+        let canvas = if left + right + top + bottom == 0 {
+            target
+        } else {
+            extend(&target, left, top, right, bottom)
+        };
+        let regions = regions
+            .into_iter()
+            .map(|r| Region {
+                x: r.x + i64::from(left),
+                y: r.y + i64::from(top),
+                ..r
+            })
+            .collect_vec();
+        // Every distinct region size gets its own set of tiles, largest first.
+        let sizes = regions
+            .iter()
+            .map(|r| (r.width, r.height))
+            .sorted_by(|a, b| b.cmp(a))
+            .dedup()
+            .collect_vec();
+        let size_of = regions
+            .iter()
+            .map(|r| {
+                sizes
+                    .iter()
+                    .position(|&s| s == (r.width, r.height))
+                    .expect("listed")
             })
-            .min_by(|a, b| {
-                a.0.partial_cmp(&b.0)
-                    .expect("Failed to compare similarity scores.")
+            .collect_vec();
+        let describe_sizes = sizes.iter().map(|(w, h)| format!("{w}x{h}")).join(", ");
+        if sizes.len() == 1 {
+            progress.message(&format!("Tile size: {describe_sizes}."));
+        } else {
+            progress.message(&format!("Tile sizes: {describe_sizes}."));
+        }
+        let scale = self.render.map(|render| {
+            let (x, y) = match render {
+                RenderSize::Tile(w, h) => (Scale::new(w, width), Scale::new(h, height)),
+                RenderSize::Width(w) => {
+                    let scale = Scale::new(w, target_width);
+                    (scale, scale)
+                }
+            };
+            progress.message(&format!(
+                "Rendering {}x{} tiles into a {}x{} mosaic.",
+                x.edge(width.into()),
+                y.edge(height.into()),
+                x.edge(target_width.into()),
+                y.edge(target_height.into())
+            ));
+            (x, y)
+        });
+        progress.inc();
+        progress.finish(Stage::Target);
+
+        let color_space = self.color_space;
+        let variants = self.augment.variants();
+        let spec = TileSpec {
+            sizes: &sizes,
+            color_space,
+            tile_fit: self.tile_fit,
+            variants: &variants,
+        };
+        let (mut sets, originals) =
+            load_tiles(tiles, &self.scan, &spec, self.max_memory, progress)?;
+        if originals.is_empty() {
+            return Err(MosaicError::EmptyTileSet);
+        }
+        // Every source image may be used this many times by the optimal
+        // assignment, which is only run for moderate sizes.
+        let capacity = |sources: usize| regions.len().div_ceil(sources);
+        let sources = originals.len();
+        if self.assignment == Assignment::Optimal
+            && !within_limits(regions.len(), sources, capacity(sources))
+        {
+            return Err(MosaicError::AssignmentTooLarge {
+                cells: regions.len(),
+                tiles: sources,
+            });
+        }
+        // The variants of each source image follow each other in every set.
+        let per_source = variants.len();
+        let source_of = |idx: usize| idx / per_source;
+        if per_source > 1 {
+            progress.message(&format!(
+                "Made {} tiles from {} source images.",
+                sets[0].len(),
+                originals.len()
+            ));
+        }
+        let masks = sizes
+            .iter()
+            .map(|&(width, height)| self.layout.mask(width, height))
+            .collect_vec();
+        let structure = scorer.structure();
+        let (descriptor, resolution) = (self.descriptor, self.match_resolution);
+        let describe = |tile: &mut Tile| {
+            descriptor.apply(tile, resolution, color_space);
+            if structure.is_some() {
+                tile.shape = Some(Shape::new(&tile.image));
+            }
+        };
+        for (set, mask) in sets.iter_mut().zip(&masks) {
+            set.par_iter_mut().for_each(|tile| {
+                if let Some(mask) = mask {
+                    fill_outside(&mut tile.image, mask);
+                    tile.features = color_space.features(&tile.image);
+                }
+                describe(tile);
+            });
+        }
+        // The whole target is converted once and the blocks are sliced out of
+        // it, except masked ones whose outside is painted over first.
+        let target_features = color_space.features(&canvas);
+        let blocks = regions
+            .par_iter()
+            .zip(&size_of)
+            .map(|(region, &size)| {
+                let (x, y) = (region.x as u32, region.y as u32);
+                let (width, height) = (region.width, region.height);
+                let mut image = crop_imm(&canvas, x, y, width, height).to_image();
+                let features = match &masks[size] {
+                    Some(mask) => {
+                        fill_outside(&mut image, mask);
+                        color_space.features(&image)
+                    }
+                    None => target_features.crop(
+                        x as usize,
+                        y as usize,
+                        width as usize,
+                        height as usize,
+                    ),
+                };
+                let mut block = Tile {
+                    image,
+                    features,
+                    shape: None,
+                    histogram: None,
+                };
+                describe(&mut block);
+                block
             })
-            .expect("Failed to find the best matching image.");
-        if avoid_duplicates {
-            used.insert(idx);
+            .collect::<Vec<_>>();
+        drop(target_features);
+        let steps = if scale.is_some() { 2 } else { 1 } * regions.len();
+        progress.start(Stage::Mosaic, steps as u64);
+        let placements = match self.assignment {
+            Assignment::Optimal => {
+                // Source images are assigned, each through its variant that
+                // best matches the block.
+                let best = blocks
+                    .par_iter()
+                    .zip(&size_of)
+                    .flat_map_iter(|(block, &size)| {
+                        progress.inc();
+                        sets[size]
+                            .chunks(per_source)
+                            .map(move |variants| {
+                                variants
+                                    .iter()
+                                    .enumerate()
+                                    .filter_map(|(v, tile)| {
+                                        similarity(block, tile, &scorer).map(|cost| (v, cost))
+                                    })
+                                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                            })
+                            .collect_vec()
+                    })
+                    .collect::<Vec<_>>();
+                // A pair that cannot be compared costs more than any whole
+                // assignment without one, yet keeps the sums finite.
+                let worst = best
+                    .iter()
+                    .flatten()
+                    .map(|&(_, cost)| cost)
+                    .fold(0.0, f32::max);
+                let unmatched = (worst + 1.0) * regions.len() as f32;
+                let cost = best
+                    .iter()
+                    .map(|best| best.map_or(unmatched, |(_, cost)| cost))
+                    .collect_vec();
+                let sources = originals.len();
+                hungarian(&cost, regions.len(), sources, capacity(sources))
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, source)| {
+                        let variant = best[i * sources + source].map_or(0, |(v, _)| v);
+                        source * per_source + variant
+                    })
+                    .collect()
+            }
+            Assignment::Greedy => {
+                let matchers = sets
+                    .iter()
+                    .map(|tiles| Matcher::new(self.search, tiles, self.candidates, scorer))
+                    .collect_vec();
+                let mut constraints = self.constraints;
+                if avoid_duplicates {
+                    constraints.push(Box::new(AvoidDuplicates {
+                        used: BTreeSet::new(),
+                        tiles: originals.len(),
+                    }));
+                }
+                let mut placements = vec![0; regions.len()];
+                let mut order = (0..regions.len()).collect_vec();
+                order.shuffle(&mut rng);
+                for i in order {
+                    let cell = regions[i].cell;
+                    let size = size_of[i];
+                    let allowed = |t| constraints.iter().all(|c| c.allows(source_of(t), cell));
+                    let Some(idx) = matchers[size].best(&blocks[i], &sets[size], allowed) else {
+                        return Err(unsatisfied(&constraints, sets[size].len(), source_of, cell));
+                    };
+                    for c in constraints.iter_mut() {
+                        c.place(source_of(idx), cell);
+                    }
+                    placements[i] = idx;
+                    progress.inc();
+                }
+                placements
+            }
+        };
+        let Some((scale_x, scale_y)) = scale else {
+            let mut canvas = canvas;
+            for (((region, block), size), idx) in
+                regions.iter().zip(&blocks).zip(size_of).zip(placements)
+            {
+                let best = &sets[size][idx].image;
+                let mask = masks[size].as_ref();
+                if self.blend > 0.0 {
+                    let best = blend(best, &block.image, mask, self.blend, self.blend_mode);
+                    paste(&mut canvas, &best, region.x, region.y, mask);
+                } else {
+                    paste(&mut canvas, best, region.x, region.y, mask);
+                }
+            }
+            let mosaic = crop_imm(&canvas, left, top, target_width, target_height).to_image();
+            progress.finish(Stage::Mosaic);
+            return Ok(DynamicImage::ImageRgb32F(mosaic).to_rgb8());
+        };
+        // Each used source image is decoded again, a few at a time, and drawn
+        // into every region it was placed in. The matched tiles are kept for
+        // the source images that can no longer be decoded.
+        let matched = placements
+            .iter()
+            .zip(&size_of)
+            .map(|(&idx, &size)| sets[size][idx].image.clone())
+            .collect_vec();
+        drop(sets);
+        let mut uses = vec![vec![]; originals.len()];
+        for (i, &idx) in placements.iter().enumerate() {
+            uses[source_of(idx)].push(i);
         }
-        replace(&mut target, best, (x * width) as i64, (y * height) as i64);
-        pb.inc(1);
+        // Where a region lands on the target, and on the rendered mosaic.
+        let rect = |i: usize| {
+            let region = &regions[i];
+            let (x, y) = (region.x - i64::from(left), region.y - i64::from(top));
+            let (rx, ry) = (scale_x.edge(x), scale_y.edge(y));
+            let width = scale_x.edge(x + i64::from(region.width)) - rx;
+            let height = scale_y.edge(y + i64::from(region.height)) - ry;
+            ((x, y), (rx, ry, width as u32, height as u32))
+        };
+        let mosaic = Mutex::new(RgbImage::new(
+            scale_x.edge(target_width.into()) as u32,
+            scale_y.edge(target_height.into()) as u32,
+        ));
+        let draw = |i: usize, tile: &Rgb32FImage| {
+            let (region, size) = (&regions[i], size_of[i]);
+            let ((x, y), (rx, ry, width, height)) = rect(i);
+            // Every rendered pixel takes the mask of the canvas pixel under it.
+            let mask = masks[size].as_ref().map(|mask| {
+                GrayImage::from_fn(width, height, |dx, dy| {
+                    let mx = scale_x.source(rx + i64::from(dx)) - x;
+                    let my = scale_y.source(ry + i64::from(dy)) - y;
+                    *mask.get_pixel(mx as u32, my as u32)
+                })
+            });
+            let tile: RgbImage = if self.blend > 0.0 {
+                // The block is cut from the canvas again, since the one
+                // that was matched is painted over outside its mask.
+                let (x, y) = (region.x as u32, region.y as u32);
+                let block = crop_imm(&canvas, x, y, region.width, region.height);
+                let block = resize(&*block, width, height, Lanczos3);
+                blend(tile, &block, mask.as_ref(), self.blend, self.blend_mode).convert()
+            } else {
+                tile.convert()
+            };
+            paste(&mut mosaic.lock().unwrap(), &tile, rx, ry, mask.as_ref());
+        };
+        let used = (0..originals.len())
+            .filter(|&s| !uses[s].is_empty())
+            .collect_vec();
+        source::decode(&originals, &used, self.max_memory, |source, original| {
+            let original = match original {
+                Ok(original) => Some(original),
+                Err(e @ (MosaicError::TileRead { .. } | MosaicError::TileDecode { .. })) => {
+                    progress.warn(&format!(
+                        "{e}. Its tiles are drawn from the smaller ones used for matching."
+                    ));
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            let mut resized = HashMap::new();
+            for &i in &uses[source] {
+                let (_, (_, _, width, height)) = rect(i);
+                match &original {
+                    // Regions narrower than a rendered pixel are left out.
+                    _ if width == 0 || height == 0 => {}
+                    Some(original) => {
+                        let variant = &variants[placements[i] % per_source];
+                        let (w, h) = variant.upright_size(width, height);
+                        let tile = resized
+                            .entry((variant.crop(), w, h))
+                            .or_insert_with(|| variant.fit(original, w, h, self.tile_fit));
+                        if variant.is_upright() {
+                            draw(i, tile);
+                        } else {
+                            draw(i, &variant.transform(tile));
+                        }
+                    }
+                    None => draw(i, &resize(&matched[i], width, height, Lanczos3)),
+                }
+                progress.inc();
+            }
+            Ok(())
+        })?;
+        progress.finish(Stage::Mosaic);
+        Ok(mosaic.into_inner().unwrap())
     }
-    DynamicImage::ImageRgb32F(target)
-        .to_rgb8()
-        .save(output)
-        .expect("Failed to save the mosaic image.");
-    pb.finish_and_clear();
-    println!("[3/3] Finished generating the mosaic image.");
-    println!("All done.");
 }
 
-fn read_images_from_directory(directory: &Path) -> Result<Vec<Rgb32FImage>> {
-    let mut images = vec![];
-    for entry in fs::read_dir(directory)? {
-        let entry = entry?;
-        let path = entry.path();
-        let img = ImageReader::open(path)?.decode()?.into_rgb32f();
-        images.push(img);
+/// The error for a cell that no tile could be placed in: the constraints
+/// that rule out every tile on their own, or else all of them together.
+fn unsatisfied(
+    constraints: &[Box<dyn PlacementConstraint>],
+    tiles: usize,
+    source_of: impl Fn(usize) -> usize,
+    cell: (u32, u32),
+) -> MosaicError {
+    if constraints.is_empty() {
+        return MosaicError::NoMatch;
+    }
+    let blocking = constraints
+        .iter()
+        .filter(|c| (0..tiles).all(|t| !c.allows(source_of(t), cell)))
+        .collect_vec();
+    let blocking = if blocking.is_empty() {
+        constraints.iter().collect()
+    } else {
+        blocking
+    };
+    MosaicError::ConstraintUnsatisfied {
+        cell,
+        constraint: blocking.iter().map(|c| c.describe()).join(" and "),
     }
-    Ok(images)
 }
 
-fn rgb_identity(image: &Rgb32FImage) -> Vec<Vec<Vec<f32>>> {
-    let mut tmp = vec![vec![vec![]; image.height() as usize]; image.width() as usize];
-    for (x, y, p) in image.enumerate_pixels() {
-        let Rgb(rgb) = p;
-        tmp[x as usize][y as usize] = rgb.to_vec();
+/// Paints the pixels outside `mask` a neutral grey, so that they compare
+/// equal between a block and a tile of the same shape.
+fn fill_outside(image: &mut Rgb32FImage, mask: &GrayImage) {
+    for (p, Luma([m])) in image.pixels_mut().zip(mask.pixels()) {
+        if *m == 0 {
+            *p = Rgb([0.5; 3]);
+        }
     }
-    tmp
 }
 
-fn rgb2lab(image: &Rgb32FImage) -> Vec<Vec<Vec<f32>>> {
-    let mut tmp = vec![vec![vec![]; image.height() as usize]; image.width() as usize];
-    for (x, y, p) in image.enumerate_pixels() {
-        let Lab(lab) = p.to_lab();
-        tmp[x as usize][y as usize] = lab.to_vec();
+/// Copies the pixels of `tile` inside `mask` onto `canvas` at (x, y),
+/// leaving out any that fall past its edges.
+fn paste<P: Pixel>(
+    canvas: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    tile: &ImageBuffer<P, Vec<P::Subpixel>>,
+    x: i64,
+    y: i64,
+    mask: Option<&GrayImage>,
+) {
+    let Some(mask) = mask else {
+        replace(canvas, tile, x, y);
+        return;
+    };
+    let (width, height) = (i64::from(canvas.width()), i64::from(canvas.height()));
+    for (dx, dy, p) in tile.enumerate_pixels() {
+        let (cx, cy) = (x + i64::from(dx), y + i64::from(dy));
+        if mask.get_pixel(dx, dy).0[0] != 0 && (0..width).contains(&cx) && (0..height).contains(&cy)
+        {
+            canvas.put_pixel(cx as u32, cy as u32, *p);
+        }
     }
-    tmp
 }
 
-fn rgb2gray(image: &Rgb32FImage) -> Vec<Vec<Vec<f32>>> {
-    let mut tmp = vec![vec![vec![]; image.height() as usize]; image.width() as usize];
-    for (x, y, p) in image.enumerate_pixels() {
-        let Luma(luma) = p.to_luma();
-        tmp[x as usize][y as usize] = luma.to_vec();
+/// How the tiles of a source image are prepared.
+struct TileSpec<'a> {
+    sizes: &'a [(u32, u32)],
+    color_space: ColorSpace,
+    tile_fit: TileFit,
+    variants: &'a [Variant],
+}
+
+impl TileSpec<'_> {
+    /// The tiles of every variant at every size, grouped by size, made from
+    /// the fitted tiles returned by `fit` for a variant and size. Returns
+    /// `None` if `fit` is missing any of them.
+    fn tiles(
+        &self,
+        mut fit: impl FnMut(&Variant, u32, u32) -> Option<Tile>,
+    ) -> Option<Vec<Vec<Tile>>> {
+        let mut fitted = HashMap::new();
+        let mut sets = vec![];
+        for &(width, height) in self.sizes {
+            let mut set = vec![];
+            for variant in self.variants {
+                let (w, h) = variant.upright_size(width, height);
+                let tile = match fitted.entry((variant.crop(), w, h)) {
+                    hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    hash_map::Entry::Vacant(entry) => entry.insert(fit(variant, w, h)?),
+                };
+                let (image, features) = if variant.is_upright() {
+                    (tile.image.clone(), tile.features.clone())
+                } else {
+                    let image = variant.transform(&tile.image);
+                    let features = self.color_space.features(&image);
+                    (image, features)
+                };
+                set.push(Tile {
+                    image,
+                    features,
+                    shape: None,
+                    histogram: None,
+                });
+            }
+            sets.push(set);
+        }
+        Some(sets)
     }
-    tmp
 }
 
-fn similarity(a: &[Vec<Vec<f32>>], b: &[Vec<Vec<f32>>]) -> Option<f32> {
-    if !(a.len() == b.len() && a[0].len() == b[0].len()) {
-        return None;
+/// Loads the source images once and prepares a set of tiles for each size
+/// of `spec`, every set listing the variants of the images in the same order.
+fn load_tiles(
+    source: TileSource,
+    scan: &Scan,
+    spec: &TileSpec,
+    max_memory: Option<usize>,
+    progress: &dyn Progress,
+) -> Result<(Vec<Vec<Tile>>, Vec<Origin>), MosaicError> {
+    let TileSpec {
+        color_space,
+        tile_fit,
+        ..
+    } = *spec;
+    let (origins, unreadable) = source::origins(source, scan)?;
+    let mut skipped = unreadable.len();
+    for error in unreadable {
+        skip(error, progress)?;
     }
-    let s = iproduct!(0..a.len(), 0..a[0].len())
-        .map(|(x, y)| {
-            a[x][y]
-                .iter()
-                .zip(&b[x][y])
-                .map(|(a, b)| (a - b).powi(2))
-                .sum::<f32>()
-                .sqrt()
+    let indexes = origins
+        .iter()
+        .filter_map(|origin| match origin {
+            Origin::File { directory, .. } => Some(directory),
+            _ => None,
+        })
+        .dedup()
+        .map(|directory| (directory.clone(), TileIndex::load(directory)))
+        .collect::<HashMap<_, _>>();
+    progress.start(Stage::Tiles, origins.len() as u64);
+    let mut tiles = origins
+        .par_iter()
+        .map(|origin| {
+            let Origin::File { directory, path } = origin else {
+                return None;
+            };
+            let index = &indexes[directory];
+            let tiles = spec.tiles(|variant, width, height| match variant.crop() {
+                Some(_) => None,
+                None => index.lookup(path, color_space, tile_fit, width, height),
+            })?;
+            progress.inc();
+            Some(tiles)
         })
-        .sum();
-    Some(s)
+        .collect::<Vec<_>>();
+    let cached = tiles.iter().filter(|t| t.is_some()).count();
+    let needed = (0..origins.len())
+        .filter(|&i| tiles[i].is_none())
+        .collect_vec();
+    let decoded = source::decode(&origins, &needed, max_memory, |_, image| {
+        progress.inc();
+        match image {
+            Ok(image) => Ok(spec.tiles(|variant, width, height| {
+                let image = variant.fit(&image, width, height, tile_fit);
+                let features = color_space.features(&image);
+                Some(Tile {
+                    image,
+                    features,
+                    shape: None,
+                    histogram: None,
+                })
+            })),
+            Err(e) => skip(e, progress).map(|()| None),
+        }
+    })?;
+    for (i, decoded) in decoded {
+        tiles[i] = decoded;
+    }
+    progress.finish(Stage::Tiles);
+    if indexes.values().any(|index| !index.is_empty()) {
+        progress.message(&format!(
+            "Loaded {cached} of {} source images from the index.",
+            origins.len()
+        ));
+    }
+    skipped += tiles.iter().filter(|t| t.is_none()).count();
+    if skipped > 0 {
+        progress.warn(&format!(
+            "Skipped {skipped} files that could not be read or decoded as images."
+        ));
+    }
+    let (per_image, origins): (Vec<_>, Vec<_>) = tiles
+        .into_iter()
+        .zip(origins)
+        .filter_map(|(tiles, origin)| Some((tiles?, origin)))
+        .unzip();
+    let mut sets = spec.sizes.iter().map(|_| vec![]).collect_vec();
+    for tiles in per_image {
+        for (set, tiles) in sets.iter_mut().zip(tiles) {
+            set.extend(tiles);
+        }
+    }
+    Ok((sets, origins))
+}
+
+/// Reports a source image that could not be read or decoded as skipped, and
+/// passes on any other error.
+pub(crate) fn skip(error: MosaicError, progress: &dyn Progress) -> Result<(), MosaicError> {
+    match error {
+        MosaicError::TileRead { path, source } => {
+            progress.message(&format!("Skipping {}: {source}", path.display()));
+        }
+        MosaicError::TileDecode { path, source } => {
+            progress.message(&format!("Skipping {}: {source}", path.display()));
+        }
+        e => return Err(e),
+    }
+    Ok(())
+}
+
+pub(crate) fn prepare_tile(
+    image: &Rgb32FImage,
+    width: u32,
+    height: u32,
+    color_space: ColorSpace,
+    tile_fit: TileFit,
+) -> Tile {
+    let image = tile_fit.apply(image, width, height);
+    let features = color_space.features(&image);
+    Tile {
+        image,
+        features,
+        shape: None,
+        histogram: None,
+    }
+}
+
+pub(crate) fn similarity(a: &Tile, b: &Tile, scorer: &Scorer) -> Option<f32> {
+    let s = match (&a.histogram, &b.histogram) {
+        (Some(a), Some(b)) => histogram_distance(a, b),
+        _ => {
+            if !a.features.is_comparable(&b.features) {
+                return None;
+            }
+            scorer.distance(&a.features, &b.features)
+        }
+    };
+    Some(scorer.mix(s, a.shape.as_ref(), b.shape.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_maps_edges_to_the_pixels_whose_centres_follow_them() {
+        for (to, from) in [(2, 1), (3, 2), (33, 20), (29, 16), (517, 200), (1, 7)] {
+            let scale = Scale::new(to, from);
+            for x in -40..40 {
+                let edge = scale.edge(x);
+                assert!(scale.source(edge) >= x, "{to}/{from} at {x}");
+                assert!(scale.source(edge - 1) < x, "{to}/{from} at {x}");
+            }
+            // Whole cells are rendered at exactly the requested size.
+            for k in -3..3 {
+                assert_eq!(scale.edge(k * i64::from(from)), k * i64::from(to));
+            }
+        }
+    }
+
+    /// Places the first allowed of `tiles` tiles in every cell of a `size` x
+    /// `size` grid, in reading order, and returns the tile of each cell.
+    fn fill(
+        constraint: &mut dyn PlacementConstraint,
+        tiles: usize,
+        size: u32,
+    ) -> Vec<Option<usize>> {
+        let mut placed = vec![];
+        for y in 0..size {
+            for x in 0..size {
+                let tile = (0..tiles).find(|&t| constraint.allows(t, (x, y)));
+                if let Some(tile) = tile {
+                    constraint.place(tile, (x, y));
+                }
+                placed.push(tile);
+            }
+        }
+        placed
+    }
+
+    #[test]
+    fn min_distance_keeps_copies_apart() {
+        let mut chebyshev = MinDistance::new(1, CellDistance::Chebyshev);
+        let mut euclidean = MinDistance::new(1, CellDistance::Euclidean);
+        for constraint in [&mut chebyshev, &mut euclidean] {
+            constraint.place(0, (2, 2));
+            assert!(!constraint.allows(0, (2, 3)));
+            assert!(constraint.allows(0, (4, 2)));
+            assert!(constraint.allows(1, (2, 3)));
+        }
+        // Diagonal neighbours are one cell away, or about 1.41.
+        assert!(!chebyshev.allows(0, (3, 3)));
+        assert!(euclidean.allows(0, (3, 3)));
+
+        for metric in [CellDistance::Chebyshev, CellDistance::Euclidean] {
+            for distance in 1..4 {
+                let size = 8;
+                let placed = fill(&mut MinDistance::new(distance, metric), 40, size);
+                let cell = |i: usize| (i as u32 % size, i as u32 / size);
+                for (i, a) in placed.iter().enumerate() {
+                    assert!(a.is_some());
+                    for (j, b) in placed.iter().enumerate().skip(i + 1) {
+                        if a == b {
+                            assert!(metric.between(cell(i), cell(j)) > distance as f32);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn max_uses_caps_every_tile() {
+        let mut constraint = MaxUses::new(2);
+        let placed = fill(&mut constraint, 5, 4);
+        let counts = placed.iter().flatten().counts();
+        assert_eq!(counts.len(), 5);
+        assert!(counts.values().all(|&n| n == 2));
+        // The last six cells are left empty once every tile has been used twice.
+        assert_eq!(placed.iter().filter(|t| t.is_none()).count(), 6);
+        assert!((0..5).all(|t| !constraint.allows(t, (0, 0))));
+    }
+
+    /// A small gradient target divided into 4 x 4 cells of 2 x 2 pixels, and
+    /// three flat tiles.
+    fn builder() -> MosaicBuilder {
+        let target = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| {
+            Rgb([(x * 30) as u8, (y * 30) as u8, 100])
+        }));
+        let tiles = (0..3)
+            .map(|i| DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([i * 80, 50, 50]))))
+            .collect();
+        MosaicBuilder::new()
+            .target_image(target)
+            .grid(4, 4)
+            .tiles(TileSource::Images(tiles))
+            .seed(1)
+    }
+
+    #[test]
+    fn unsatisfiable_constraint_is_named() {
+        let result = builder().constraint(MaxUses::new(2)).build();
+        let Err(MosaicError::ConstraintUnsatisfied { constraint, .. }) = result else {
+            panic!("expected an unsatisfied constraint");
+        };
+        assert_eq!(constraint, "the limit of 2 uses per tile");
+    }
+
+    #[test]
+    fn optimal_assignment_requires_avoiding_duplicates() {
+        let result = builder().assignment(Assignment::Optimal).build();
+        assert!(matches!(result, Err(MosaicError::OptimalWithDuplicates)));
+        let result = builder()
+            .assignment(Assignment::Optimal)
+            .duplicates(DuplicatePolicy::Avoid)
+            .build();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_mosaic() {
+        let build = |seed| {
+            builder()
+                .duplicates(DuplicatePolicy::Avoid)
+                .seed(seed)
+                .build()
+                .unwrap()
+        };
+        let first = build(7);
+        assert_eq!(build(7), first);
+        // Blocks take the tiles left in a different order.
+        assert!((8..16).any(|seed| build(seed) != first));
+    }
 }
@@ -0,0 +1,77 @@
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    Index,
+    Target,
+    Tiles,
+    Mosaic,
+}
+
+impl Stage {
+    /// Position of the stage within its pipeline, starting at 1.
+    pub fn number(&self) -> usize {
+        match self {
+            Stage::Index => 1,
+            Stage::Target => 1,
+            Stage::Tiles => 2,
+            Stage::Mosaic => 3,
+        }
+    }
+
+    /// Number of stages in the pipeline this stage belongs to.
+    pub fn total(&self) -> usize {
+        match self {
+            Stage::Index => 1,
+            Stage::Target | Stage::Tiles | Stage::Mosaic => 3,
+        }
+    }
+
+    pub fn action(&self) -> &'static str {
+        match self {
+            Stage::Index => "indexing",
+            Stage::Target | Stage::Tiles => "preprocessing",
+            Stage::Mosaic => "generating",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Index => write!(f, "the source images"),
+            Stage::Target => write!(f, "the target image"),
+            Stage::Tiles => write!(f, "the source images"),
+            Stage::Mosaic => write!(f, "the mosaic image"),
+        }
+    }
+}
+
+/// Receives progress notifications while a mosaic is being generated.
+///
+/// Every method has an empty default, so implementors only override what they need.
+pub trait Progress: Send + Sync {
+    fn start(&self, stage: Stage, len: u64) {
+        let _ = (stage, len);
+    }
+
+    fn inc(&self) {}
+
+    fn finish(&self, stage: Stage) {
+        let _ = stage;
+    }
+
+    fn message(&self, message: &str) {
+        let _ = message;
+    }
+
+    /// Reports a problem that does not stop the mosaic from being generated.
+    fn warn(&self, message: &str) {
+        let _ = message;
+    }
+}
+
+/// Discards every notification.
+pub struct Silent;
+
+impl Progress for Silent {}
@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
+
+use crate::{error::MosaicError, index::INDEX_FILE};
+
+/// Which files are read as source images when a directory or archive is
+/// scanned.
+///
+/// Directories are walked recursively. Patterns are globs matched against
+/// the path of a file relative to the directory, or its name in an archive,
+/// ignoring case, where `*` also matches `/`, so `*.jpg` finds JPEG files at
+/// any depth.
+#[derive(Clone, Debug, Default)]
+pub struct Scan {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    follow_symlinks: bool,
+}
+
+impl Scan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only reads files matching one of the included patterns. Without any,
+    /// every file is read.
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Skips files matching any of the excluded patterns.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Follows symbolic links to files and directories. Off by default.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Compiles the patterns.
+    pub(crate) fn filter(&self) -> Result<Filter, MosaicError> {
+        Ok(Filter {
+            include: (!self.include.is_empty())
+                .then(|| glob_set(&self.include))
+                .transpose()?,
+            exclude: glob_set(&self.exclude)?,
+        })
+    }
+
+    /// Lists the files under `directory` that pass `filter`, sorted by path,
+    /// along with the entries below it that could not be read, such as
+    /// dangling links. Only fails when `directory` itself cannot be read.
+    pub(crate) fn files(
+        &self,
+        directory: &Path,
+        filter: &Filter,
+    ) -> Result<Vec<Result<PathBuf, MosaicError>>, MosaicError> {
+        let walk = WalkDir::new(directory)
+            .follow_links(self.follow_symlinks)
+            .sort_by_file_name();
+        let mut files = vec![];
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    let top = error.depth() == 0;
+                    let error = MosaicError::TileRead {
+                        path: error.path().unwrap_or(directory).to_path_buf(),
+                        source: error.into(),
+                    };
+                    if top {
+                        return Err(error);
+                    }
+                    files.push(Err(error));
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || entry.file_name() == INDEX_FILE {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(directory).unwrap_or(entry.path());
+            if filter.matches(relative) {
+                files.push(Ok(entry.into_path()));
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// The compiled patterns of a `Scan`.
+pub(crate) struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    /// Whether a file at `relative` path below the scanned directory is read.
+    pub(crate) fn matches(&self, relative: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|set| set.is_match(relative))
+            && !self.exclude.is_match(relative)
+    }
+}
+
+fn glob(pattern: &str) -> Result<Glob, MosaicError> {
+    GlobBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|source| MosaicError::InvalidPattern {
+            pattern: pattern.to_string(),
+            source,
+        })
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, MosaicError> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(glob(pattern)?);
+    }
+    set.build().map_err(|source| MosaicError::InvalidPattern {
+        pattern: patterns.join(", "),
+        source,
+    })
+}
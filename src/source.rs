@@ -0,0 +1,682 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Cursor, Read, Seek},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use flate2::read::MultiGzDecoder;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader, Rgb32FImage};
+use rayon::Yield;
+use zip::ZipArchive;
+
+use crate::{
+    error::MosaicError,
+    scan::{Filter, Scan},
+};
+
+/// Where the source images come from.
+pub enum TileSource {
+    /// A single image file.
+    File(PathBuf),
+    /// The images below a directory, as set by `MosaicBuilder::scan`.
+    Directory(PathBuf),
+    /// The images in a zip archive, decoded without extracting it.
+    Zip(PathBuf),
+    /// The images in a tar archive, which may be compressed with gzip.
+    Tar(PathBuf),
+    /// A text file with one path per line, each read as by
+    /// `TileSource::from_path`. Relative paths start from the directory of
+    /// the list, and empty lines and lines starting with `#` are ignored.
+    List(PathBuf),
+    Images(Vec<DynamicImage>),
+    /// All the images of every source, in order.
+    Many(Vec<TileSource>),
+}
+
+impl TileSource {
+    /// Picks the source for `path` from its form: a list file when it starts
+    /// with `@`, a directory, a `.zip`, `.tar`, `.tar.gz` or `.tgz` archive,
+    /// or otherwise an image file.
+    pub fn from_path(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(list) => TileSource::List(list.into()),
+            None => Self::classify(path),
+        }
+    }
+
+    fn classify(path: PathBuf) -> Self {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if path.is_dir() {
+            TileSource::Directory(path)
+        } else if name.ends_with(".zip") {
+            TileSource::Zip(path)
+        } else if [".tar", ".tar.gz", ".tgz"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+        {
+            TileSource::Tar(path)
+        } else {
+            TileSource::File(path)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+/// Where one source image is read from, kept to read it again later.
+pub(crate) enum Origin {
+    /// A file, which may be cached in the index of `directory`.
+    File {
+        directory: PathBuf,
+        path: PathBuf,
+    },
+    /// A file in an archive.
+    Entry {
+        archive: PathBuf,
+        kind: ArchiveKind,
+        name: String,
+    },
+    Image(DynamicImage),
+}
+
+/// Lists every source image of `source`, applying the patterns of `scan` to
+/// directories and archives, along with the entries below directories that
+/// could not be read. The paths given in `source` must all be readable.
+pub(crate) fn origins(
+    source: TileSource,
+    scan: &Scan,
+) -> Result<(Vec<Origin>, Vec<MosaicError>), MosaicError> {
+    let mut origins = vec![];
+    let mut unreadable = vec![];
+    expand(source, scan, &scan.filter()?, &mut origins, &mut unreadable)?;
+    Ok((origins, unreadable))
+}
+
+fn expand(
+    source: TileSource,
+    scan: &Scan,
+    filter: &Filter,
+    origins: &mut Vec<Origin>,
+    unreadable: &mut Vec<MosaicError>,
+) -> Result<(), MosaicError> {
+    match source {
+        TileSource::File(path) => {
+            File::open(&path).map_err(|source| MosaicError::TileRead {
+                path: path.clone(),
+                source,
+            })?;
+            let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            origins.push(Origin::File { directory, path });
+        }
+        TileSource::Directory(directory) => {
+            for file in scan.files(&directory, filter)? {
+                match file {
+                    Ok(path) => {
+                        let directory = directory.clone();
+                        origins.push(Origin::File { directory, path });
+                    }
+                    Err(e) => unreadable.push(e),
+                }
+            }
+        }
+        TileSource::Zip(archive) => {
+            list(archive, ArchiveKind::Zip, filter, origins, unreadable)?;
+        }
+        TileSource::Tar(archive) => {
+            list(archive, ArchiveKind::Tar, filter, origins, unreadable)?;
+        }
+        TileSource::List(list) => {
+            let text = fs::read_to_string(&list).map_err(|source| MosaicError::TileRead {
+                path: list.clone(),
+                source,
+            })?;
+            let base = list.parent().unwrap_or(Path::new(""));
+            for line in text.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let source = TileSource::classify(base.join(line));
+                expand(source, scan, filter, origins, unreadable)?;
+            }
+        }
+        TileSource::Images(images) => origins.extend(images.into_iter().map(Origin::Image)),
+        TileSource::Many(sources) => {
+            for source in sources {
+                expand(source, scan, filter, origins, unreadable)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds the files of `archive` that pass `filter`, once per name, along with
+/// the entries that could not be read.
+fn list(
+    archive: PathBuf,
+    kind: ArchiveKind,
+    filter: &Filter,
+    origins: &mut Vec<Origin>,
+    unreadable: &mut Vec<MosaicError>,
+) -> Result<(), MosaicError> {
+    let mut seen = HashSet::new();
+    let broken = for_each_entry(&archive, kind, |name, entry| {
+        if filter.matches(Path::new(name)) && seen.insert(name.to_string()) {
+            match entry {
+                Ok(_) => origins.push(Origin::Entry {
+                    archive: archive.clone(),
+                    kind,
+                    name: name.to_string(),
+                }),
+                Err(source) => unreadable.push(MosaicError::TileRead {
+                    path: archive.join(name),
+                    source,
+                }),
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(source) = broken {
+        unreadable.push(MosaicError::TileRead {
+            path: archive,
+            source,
+        });
+    }
+    Ok(())
+}
+
+/// Calls `visit` with the name and contents of every file in `archive`, in
+/// the order they are stored, or with the error met opening it. An error in
+/// the archive itself ends the walk and is returned, since the entries past
+/// it cannot be found.
+fn for_each_entry(
+    archive: &Path,
+    kind: ArchiveKind,
+    mut visit: impl FnMut(&str, io::Result<&mut dyn Read>) -> Result<(), MosaicError>,
+) -> Result<Option<io::Error>, MosaicError> {
+    let read_error = |source: io::Error| MosaicError::TileRead {
+        path: archive.to_path_buf(),
+        source,
+    };
+    let mut reader = BufReader::new(File::open(archive).map_err(read_error)?);
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = ZipArchive::new(reader).map_err(|e| read_error(e.into()))?;
+            for i in 0..zip.len() {
+                let error = match zip.by_index(i) {
+                    Ok(mut entry) => {
+                        if entry.is_file() {
+                            let name = entry.name().to_string();
+                            visit(&name, Ok(&mut entry))?;
+                        }
+                        continue;
+                    }
+                    Err(e) => io::Error::from(e),
+                };
+                match zip.name_for_index(i) {
+                    Some(name) if name.ends_with('/') => {}
+                    Some(name) => visit(name, Err(error))?,
+                    None => return Ok(Some(error)),
+                }
+            }
+        }
+        ArchiveKind::Tar => {
+            let gzip = reader
+                .fill_buf()
+                .map_err(read_error)?
+                .starts_with(&[0x1f, 0x8b]);
+            let reader: Box<dyn Read> = if gzip {
+                Box::new(MultiGzDecoder::new(reader))
+            } else {
+                Box::new(reader)
+            };
+            let mut tar = tar::Archive::new(reader);
+            for entry in tar.entries().map_err(read_error)? {
+                let mut entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Ok(Some(e)),
+                };
+                if entry.header().entry_type().is_file() {
+                    let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+                    visit(&name, Ok(&mut entry))?;
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Decodes the source images at `needed` in parallel and passes each to `f`,
+/// returning what it returns in no particular order. Every archive is read
+/// once, from start to end.
+///
+/// Images are decoded as they are read, a few more than there are threads
+/// at a time and, with `max_memory`, no more than fit in that many bytes, so
+/// that only the originals being worked on are held in memory.
+pub(crate) fn decode<T: Send>(
+    origins: &[Origin],
+    needed: &[usize],
+    max_memory: Option<usize>,
+    f: impl Fn(usize, Result<Rgb32FImage, MosaicError>) -> Result<T, MosaicError> + Sync,
+) -> Result<Vec<(usize, T)>, MosaicError> {
+    let mut direct = vec![];
+    let mut archives = BTreeMap::<_, HashMap<_, _>>::new();
+    for &i in needed {
+        match &origins[i] {
+            Origin::File { path, .. } => direct.push((i, Source::Path(path))),
+            Origin::Image(image) => direct.push((i, Source::Image(image))),
+            Origin::Entry {
+                archive,
+                kind,
+                name,
+            } => {
+                archives
+                    .entry((archive.as_path(), *kind))
+                    .or_default()
+                    .insert(name.as_str(), i);
+            }
+        }
+    }
+    let budget = Budget::new(max_memory);
+    let results = Mutex::new(vec![]);
+    let failure = Mutex::new(None);
+    let (f, budget, results, failure) = (&f, &budget, &results, &failure);
+    let report = move |i, image| match f(i, image) {
+        Ok(result) => results.lock().unwrap().push((i, result)),
+        Err(e) => {
+            failure.lock().unwrap().get_or_insert(e);
+        }
+    };
+    let finish = move |i, image, cost| {
+        report(i, image);
+        budget.release(cost);
+    };
+    let failed = || failure.lock().unwrap().take().map_or(Ok(()), Err);
+    rayon::in_place_scope(|scope| {
+        for (i, source) in direct {
+            failed()?;
+            let cost = match source {
+                Source::Path(path) => ImageReader::open(path)
+                    .ok()
+                    .and_then(|reader| reader.into_dimensions().ok())
+                    .map_or(0, decoding_cost),
+                Source::Image(image) => decoding_cost(image.dimensions()),
+            };
+            budget.acquire(cost);
+            scope.spawn(move |_| {
+                let image = match source {
+                    Source::Path(path) => decode_tile(path),
+                    Source::Image(image) => Ok(image.to_rgb32f()),
+                };
+                finish(i, image, cost);
+            });
+        }
+        for ((archive, kind), mut wanted) in archives {
+            let broken = for_each_entry(archive, kind, |name, entry| {
+                failed()?;
+                let Some(i) = wanted.remove(name) else {
+                    return Ok(());
+                };
+                let path = archive.join(name);
+                let mut bytes = vec![];
+                if let Err(source) = entry.and_then(|reader| reader.read_to_end(&mut bytes)) {
+                    report(i, Err(MosaicError::TileRead { path, source }));
+                    return Ok(());
+                }
+                let cost = entry_reader(&path, Cursor::new(&bytes))
+                    .ok()
+                    .and_then(|reader| reader.into_dimensions().ok())
+                    .map_or(0, decoding_cost)
+                    + bytes.len();
+                budget.acquire(cost);
+                scope.spawn(move |_| finish(i, decode_entry(&path, bytes), cost));
+                Ok(())
+            })?;
+            // The entries past an error in the archive, or that were removed
+            // from it since it was listed, cannot be read.
+            for (name, i) in wanted {
+                let source = match &broken {
+                    Some(e) => io::Error::new(e.kind(), e.to_string()),
+                    None => io::Error::new(io::ErrorKind::NotFound, "no longer in the archive"),
+                };
+                let path = archive.join(name);
+                report(i, Err(MosaicError::TileRead { path, source }));
+            }
+        }
+        Ok(())
+    })?;
+    failed()?;
+    let results = results.lock().unwrap().drain(..).collect();
+    Ok(results)
+}
+
+enum Source<'a> {
+    Path(&'a Path),
+    Image(&'a DynamicImage),
+}
+
+/// Bytes taken by an image of the given size while it is decoded and
+/// converted: at most 4 bytes a pixel for the decoded image, then 12 for its
+/// `f32` copy.
+fn decoding_cost((width, height): (u32, u32)) -> usize {
+    width as usize * height as usize * 16
+}
+
+/// Limits the images decoded at once, by count and by their decoding cost.
+struct Budget {
+    images: usize,
+    bytes: usize,
+    used: Mutex<(usize, usize)>,
+    released: Condvar,
+}
+
+impl Budget {
+    fn new(max_memory: Option<usize>) -> Self {
+        Self {
+            images: rayon::current_num_threads() * 2,
+            bytes: max_memory.unwrap_or(usize::MAX),
+            used: Mutex::new((0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes `bytes` from the budget if they fit. An image is always let
+    /// through when no other is in flight, however large it is.
+    fn take(&self, used: &mut (usize, usize), bytes: usize) -> bool {
+        let (images, taken) = *used;
+        let fits =
+            images == 0 || (images < self.images && taken.saturating_add(bytes) <= self.bytes);
+        if fits {
+            *used = (images + 1, taken + bytes);
+        }
+        fits
+    }
+
+    /// Waits until `bytes` fit in the budget. On a thread of the pool, the
+    /// decodes being waited for may be queued behind the caller, so it runs
+    /// them meanwhile.
+    fn acquire(&self, bytes: usize) {
+        loop {
+            if self.take(&mut self.used.lock().unwrap(), bytes) {
+                return;
+            }
+            if rayon::yield_now() != Some(Yield::Executed) {
+                let mut used = self.used.lock().unwrap();
+                if self.take(&mut used, bytes) {
+                    return;
+                }
+                drop(
+                    self.released
+                        .wait_timeout(used, Duration::from_millis(10))
+                        .unwrap(),
+                );
+            }
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        let mut used = self.used.lock().unwrap();
+        *used = (used.0 - 1, used.1 - bytes);
+        self.released.notify_all();
+    }
+}
+
+/// Reads an archive entry in the format given by its extension, or else
+/// guessed from its contents.
+fn entry_reader<R: BufRead + Seek>(path: &Path, reader: R) -> Result<ImageReader<R>, MosaicError> {
+    match ImageFormat::from_path(path) {
+        Ok(format) => Ok(ImageReader::with_format(reader, format)),
+        Err(_) => ImageReader::new(reader)
+            .with_guessed_format()
+            .map_err(|source| MosaicError::TileRead {
+                path: path.to_path_buf(),
+                source,
+            }),
+    }
+}
+
+fn decode_entry(path: &Path, bytes: Vec<u8>) -> Result<Rgb32FImage, MosaicError> {
+    let image = entry_reader(path, Cursor::new(bytes))?
+        .decode()
+        .map_err(|source| MosaicError::TileDecode {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    Ok(image.into_rgb32f())
+}
+
+pub(crate) fn decode_tile(path: &Path) -> Result<Rgb32FImage, MosaicError> {
+    let image = ImageReader::open(path)
+        .map_err(|source| MosaicError::TileRead {
+            path: path.to_path_buf(),
+            source,
+        })?
+        .decode()
+        .map_err(|source| MosaicError::TileDecode {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    Ok(image.into_rgb32f())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    fn png(red: u8) -> Vec<u8> {
+        let mut bytes = vec![];
+        RgbImage::from_pixel(4, 4, Rgb([red, 10, 20]))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.add_directory("x/", options).unwrap();
+        for (name, bytes) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(bytes).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(vec![]);
+        for (name, bytes) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, *bytes).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut gzip = GzEncoder::new(vec![], Compression::fast());
+        gzip.write_all(bytes).unwrap();
+        gzip.finish().unwrap()
+    }
+
+    /// The names of the source images of `source`, each with the red of the
+    /// decoded image if it could be decoded, and the number of unreadable
+    /// entries found while listing them.
+    fn load(source: TileSource) -> (Vec<(String, Option<u8>)>, usize) {
+        let (origins, unreadable) = origins(source, &Scan::new()).unwrap();
+        let needed = (0..origins.len()).collect::<Vec<_>>();
+        let mut red = vec![None; origins.len()];
+        let decoded = decode(&origins, &needed, None, |_, image| {
+            Ok(image
+                .ok()
+                .map(|image| (image.get_pixel(0, 0).0[0] * 255.0).round() as u8))
+        })
+        .unwrap();
+        for (i, decoded) in decoded {
+            red[i] = decoded;
+        }
+        let names = origins.iter().map(|origin| match origin {
+            Origin::File { path, .. } => path.file_name().unwrap().to_string_lossy().into(),
+            Origin::Entry { name, .. } => name.clone(),
+            Origin::Image(_) => unreachable!(),
+        });
+        (names.zip(red).collect(), unreadable.len())
+    }
+
+    #[test]
+    fn reads_archives_and_list_files() {
+        let dir = TempDir::new().unwrap();
+        let files: [(&str, Vec<u8>); 4] = [
+            (
+                "a.zip",
+                zip(&[("x/red.png", &png(200)), ("x/notes.txt", b"notes")]),
+            ),
+            ("b.tar.gz", gzip(&tar(&[("green.png", &png(100))]))),
+            ("blue.png", png(50)),
+            (
+                "tiles.txt",
+                b"a.zip\n# a comment\n\nb.tar.gz\n  blue.png\n".to_vec(),
+            ),
+        ];
+        for (name, bytes) in files {
+            fs::write(dir.path().join(name), bytes).unwrap();
+        }
+        let list = format!("@{}", dir.path().join("tiles.txt").display());
+        let (loaded, unreadable) = load(TileSource::from_path(list));
+        let expected = [
+            ("x/red.png", Some(200)),
+            ("x/notes.txt", None),
+            ("green.png", Some(100)),
+            ("blue.png", Some(50)),
+        ];
+        assert_eq!(loaded, expected.map(|(name, red)| (name.to_string(), red)));
+        assert_eq!(unreadable, 0);
+    }
+
+    #[test]
+    fn skips_unreadable_archive_entries() {
+        let dir = TempDir::new().unwrap();
+        // The checksum of the first entry no longer matches its contents.
+        let bad = png(1);
+        let mut archive = zip(&[("bad.png", &bad), ("good.png", &png(2))]);
+        let at = archive
+            .windows(bad.len())
+            .position(|window| window == bad)
+            .unwrap();
+        archive[at + bad.len() - 1] ^= 0xff;
+        fs::write(dir.path().join("c.zip"), archive).unwrap();
+        // The tar archive breaks off in the middle of the second header.
+        let green = png(3);
+        let first = 512 + green.len().div_ceil(512) * 512;
+        let mut archive = tar(&[("green.png", &green), ("blue.png", &png(4))]);
+        archive.truncate(first + 256);
+        fs::write(dir.path().join("d.tar"), archive).unwrap();
+
+        let source = TileSource::Many(vec![
+            TileSource::Zip(dir.path().join("c.zip")),
+            TileSource::Tar(dir.path().join("d.tar")),
+        ]);
+        let (loaded, unreadable) = load(source);
+        let expected = [
+            ("bad.png", None),
+            ("good.png", Some(2)),
+            ("green.png", Some(3)),
+        ];
+        assert_eq!(loaded, expected.map(|(name, red)| (name.to_string(), red)));
+        assert_eq!(unreadable, 1);
+    }
+
+    #[test]
+    fn entries_gone_from_the_archive_are_unreadable() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("e.tar.gz");
+        fs::write(&path, gzip(&tar(&[("a.png", &png(5)), ("b.png", &png(6))]))).unwrap();
+        let (origins, _) = origins(TileSource::Tar(path.clone()), &Scan::new()).unwrap();
+        fs::write(&path, gzip(&tar(&[("a.png", &png(5))]))).unwrap();
+        let mut decoded = decode(&origins, &[0, 1], None, |i, image| Ok((i, image.is_ok())))
+            .unwrap()
+            .into_iter()
+            .map(|(_, result)| result)
+            .collect::<Vec<_>>();
+        decoded.sort();
+        assert_eq!(decoded, [(0, true), (1, false)]);
+    }
+
+    #[test]
+    fn budget_lets_an_oversized_image_through_alone() {
+        let budget = Budget::new(Some(10));
+        let mut used = budget.used.lock().unwrap();
+        assert!(budget.take(&mut used, 1000));
+        assert!(!budget.take(&mut used, 1));
+        drop(used);
+        budget.release(1000);
+        assert!(budget.take(&mut budget.used.lock().unwrap(), 1));
+    }
+
+    fn images(count: u8) -> Vec<Origin> {
+        (0..count)
+            .map(|red| Origin::Image(RgbImage::from_pixel(64, 64, Rgb([red, 0, 0])).into()))
+            .collect()
+    }
+
+    #[test]
+    fn tiny_memory_still_decodes_every_image() {
+        let origins = images(8);
+        let needed = (0..origins.len()).collect::<Vec<_>>();
+        let mut decoded = decode(&origins, &needed, Some(1), |i, image| {
+            Ok((i, (image?.get_pixel(0, 0).0[0] * 255.0).round() as usize))
+        })
+        .unwrap()
+        .into_iter()
+        .map(|(_, result)| result)
+        .collect::<Vec<_>>();
+        decoded.sort();
+        assert_eq!(decoded, needed.iter().map(|&i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn decode_returns_the_first_error() {
+        let origins = images(8);
+        let needed = (0..origins.len()).collect::<Vec<_>>();
+        let first = Mutex::new(None);
+        // On a single thread, the errors are reported in the order they are
+        // made.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let error = pool
+            .install(|| {
+                decode(&origins, &needed, None, |i, _| {
+                    first.lock().unwrap().get_or_insert(i);
+                    Err::<(), _>(MosaicError::TileRead {
+                        path: i.to_string().into(),
+                        source: io::Error::other("failed"),
+                    })
+                })
+            })
+            .unwrap_err();
+        let first = first.into_inner().unwrap().unwrap();
+        assert!(
+            matches!(&error, MosaicError::TileRead { path, .. } if *path == Path::new(&first.to_string())),
+            "{error} after {first}"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use std::f32::consts::PI;
+
+use clap::{builder::PossibleValue, ValueEnum};
+use image::{Rgb, Rgb32FImage};
+
+/// Largest side of the windows over which SSIM is computed.
+const SSIM_WINDOW: usize = 8;
+/// Number of gradient orientations distinguished by the edge histograms.
+const ORIENTATIONS: usize = 8;
+/// The edge histograms are kept separately for a grid of this many cells per side.
+const EDGE_GRID: usize = 2;
+/// Mean gradient magnitude below which a tile counts as mostly flat.
+const FLATNESS: f32 = 0.05;
+
+/// How the internal structure of a block and a tile is compared.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Structure {
+    /// Structural similarity of the luminance.
+    Ssim,
+    /// Histograms of Sobel gradient orientations.
+    Edges,
+}
+
+impl ValueEnum for Structure {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Structure::Ssim, Structure::Edges]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Structure::Ssim => PossibleValue::new("ssim")
+                .help("Compare luminance, contrast and structure with SSIM."),
+            Structure::Edges => PossibleValue::new("edges")
+                .help("Compare the orientation of edges found with a Sobel filter."),
+        })
+    }
+}
+
+impl std::fmt::Display for Structure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl Structure {
+    /// Dissimilarity of two shapes between 0 and 1.
+    pub(crate) fn distance(self, a: &Shape, b: &Shape) -> f32 {
+        match self {
+            Structure::Ssim => (1.0 - ssim(a, b)) / 2.0,
+            Structure::Edges => {
+                a.edges
+                    .iter()
+                    .zip(&b.edges)
+                    .map(|(a, b)| (a - b).abs())
+                    .sum::<f32>()
+                    / 2.0
+            }
+        }
+    }
+}
+
+/// Luminance and edges of a tile or block, used by `Structure`.
+pub(crate) struct Shape {
+    width: usize,
+    height: usize,
+    /// Rec. 709 luma in row-major order.
+    luma: Vec<f32>,
+    /// Orientation histograms of each grid cell weighted by gradient
+    /// magnitude, followed by a bin for flatness, summing to 1.
+    edges: Vec<f32>,
+}
+
+impl Shape {
+    pub(crate) fn new(image: &Rgb32FImage) -> Self {
+        let width = image.width() as usize;
+        let height = image.height() as usize;
+        let luma = image
+            .pixels()
+            .map(|Rgb([r, g, b])| 0.2126 * r + 0.7152 * g + 0.0722 * b)
+            .collect::<Vec<_>>();
+        let mut shape = Self {
+            width,
+            height,
+            luma,
+            edges: vec![],
+        };
+        shape.edges = shape.edge_histogram();
+        shape
+    }
+
+    /// Luma at (x, y), repeating the border outside the image.
+    pub(crate) fn at(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.luma[y * self.width + x]
+    }
+
+    /// Horizontal and vertical Sobel gradients of the luma at (x, y).
+    pub(crate) fn gradient(&self, x: usize, y: usize) -> (f32, f32) {
+        let (x, y) = (x as isize, y as isize);
+        let p = |dx, dy| self.at(x + dx, y + dy);
+        let gx = p(1, -1) + 2.0 * p(1, 0) + p(1, 1) - p(-1, -1) - 2.0 * p(-1, 0) - p(-1, 1);
+        let gy = p(-1, 1) + 2.0 * p(0, 1) + p(1, 1) - p(-1, -1) - 2.0 * p(0, -1) - p(1, -1);
+        (gx, gy)
+    }
+
+    fn edge_histogram(&self) -> Vec<f32> {
+        let mut histogram = vec![0.0; EDGE_GRID * EDGE_GRID * ORIENTATIONS + 1];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (gx, gy) = self.gradient(x, y);
+                let magnitude = gx.hypot(gy);
+                let orientation = gy.atan2(gx).rem_euclid(PI);
+                let bin = ((orientation / PI * ORIENTATIONS as f32) as usize).min(ORIENTATIONS - 1);
+                let cell = y * EDGE_GRID / self.height * EDGE_GRID + x * EDGE_GRID / self.width;
+                histogram[cell * ORIENTATIONS + bin] += magnitude;
+            }
+        }
+        let flatness = FLATNESS * self.luma.len() as f32;
+        *histogram.last_mut().expect("not empty") = flatness;
+        let total = histogram.iter().sum::<f32>();
+        histogram.iter_mut().for_each(|h| *h /= total);
+        histogram
+    }
+}
+
+/// https://en.wikipedia.org/wiki/Structural_similarity
+/// Mean SSIM over half-overlapping windows of at most `SSIM_WINDOW` pixels.
+fn ssim(a: &Shape, b: &Shape) -> f32 {
+    let (c1, c2) = (0.01f32.powi(2), 0.03f32.powi(2));
+    let width = a.width.min(b.width);
+    let height = a.height.min(b.height);
+    let window = |len: usize| {
+        let size = len.clamp(1, SSIM_WINDOW);
+        let starts = (0..=len.saturating_sub(size)).step_by((size / 2).max(1));
+        (size, starts)
+    };
+    let (window_width, xs) = window(width);
+    let (window_height, ys) = window(height);
+    let ys = ys.collect::<Vec<_>>();
+    let n = (window_width * window_height) as f32;
+    let mut total = 0.0;
+    let mut count = 0;
+    for x0 in xs {
+        for &y0 in &ys {
+            let pixels = || {
+                (y0..y0 + window_height).flat_map(move |y| {
+                    (x0..x0 + window_width)
+                        .map(move |x| (a.luma[y * a.width + x], b.luma[y * b.width + x]))
+                })
+            };
+            let (mean_a, mean_b) =
+                pixels().fold((0.0, 0.0), |(sa, sb), (pa, pb)| (sa + pa / n, sb + pb / n));
+            let (var_a, var_b, covariance) =
+                pixels().fold((0.0, 0.0, 0.0), |(va, vb, cv), (pa, pb)| {
+                    let (da, db) = (pa - mean_a, pb - mean_b);
+                    (va + da * da / n, vb + db * db / n, cv + da * db / n)
+                });
+            total += (2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2)
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            count += 1;
+        }
+    }
+    if count == 0 {
+        1.0
+    } else {
+        total / count as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metric::{DistanceMetric, Scorer},
+        mosaic::ColorSpace,
+    };
+
+    fn image(size: u32, luma: impl Fn(u32, u32) -> f32) -> Shape {
+        Shape::new(&Rgb32FImage::from_fn(size, size, |x, y| {
+            let v = luma(x, y);
+            Rgb([v, v, v])
+        }))
+    }
+
+    /// `dark` in the top half and `bright` in the bottom one, or in the left
+    /// and right halves when `vertical`.
+    fn edge(vertical: bool, dark: f32, bright: f32) -> Shape {
+        image(16, |x, y| {
+            let past = if vertical { x >= 8 } else { y >= 8 };
+            if past {
+                bright
+            } else {
+                dark
+            }
+        })
+    }
+
+    fn texture() -> Shape {
+        image(16, |x, y| ((x * 7 + y * 13) % 10) as f32 / 10.0)
+    }
+
+    #[test]
+    fn ssim_of_an_image_with_itself_is_one() {
+        for shape in [texture(), edge(false, 0.2, 0.8), image(16, |_, _| 0.3)] {
+            assert!((ssim(&shape, &shape) - 1.0).abs() < 1e-5);
+            assert!(Structure::Ssim.distance(&shape, &shape).abs() < 1e-5);
+            assert!(Structure::Edges.distance(&shape, &shape).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn distances_are_symmetric() {
+        let shapes = [texture(), edge(false, 0.2, 0.8), edge(true, 0.4, 0.5)];
+        for a in &shapes {
+            for b in &shapes {
+                assert!((ssim(a, b) - ssim(b, a)).abs() < 1e-5);
+                for structure in [Structure::Ssim, Structure::Edges] {
+                    let (ab, ba) = (structure.distance(a, b), structure.distance(b, a));
+                    assert!((ab - ba).abs() < 1e-5, "{structure}");
+                    assert!((0.0..=1.0).contains(&ab), "{structure}: {ab}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edges_prefer_the_same_orientation() {
+        // All three have the same mean, so only the orientation of their
+        // edges tells the tiles apart.
+        let block = edge(false, 0.2, 0.8);
+        let (horizontal, vertical) = (edge(false, 0.3, 0.7), edge(true, 0.2, 0.8));
+        for structure in [Structure::Ssim, Structure::Edges] {
+            let same = structure.distance(&block, &horizontal);
+            let crossed = structure.distance(&block, &vertical);
+            assert!(same < crossed, "{structure}: {same} >= {crossed}");
+        }
+    }
+
+    #[test]
+    fn mix_weighs_color_against_structure() {
+        let (a, b) = (edge(false, 0.2, 0.8), edge(true, 0.2, 0.8));
+        let scorer = |weight| {
+            Scorer::new(DistanceMetric::Cie76, ColorSpace::Lab, [1.0; 3], 1.0)
+                .with_structure(Some((Structure::Edges, weight)))
+        };
+        let shape = Structure::Edges.distance(&a, &b);
+        assert!((scorer(0.0).mix(0.4, Some(&a), Some(&b)) - 0.4).abs() < 1e-5);
+        assert!((scorer(1.0).mix(0.4, Some(&a), Some(&b)) - shape).abs() < 1e-5);
+        let half = scorer(0.5).mix(0.4, Some(&a), Some(&b));
+        assert!((half - (0.4 * shape).sqrt()).abs() < 1e-4);
+        // Without shapes only the color counts.
+        assert_eq!(scorer(0.5).mix(0.4, None, None), 0.4);
+    }
+}
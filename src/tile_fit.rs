@@ -0,0 +1,247 @@
+use clap::{builder::PossibleValue, ValueEnum};
+use image::{
+    imageops::{crop_imm, overlay, resize, FilterType},
+    Rgb, Rgb32FImage,
+};
+
+use crate::structure::Shape;
+
+/// Longest side of the thumbnail in which `TileFit::SmartCrop` looks for
+/// the subject.
+const SALIENCY_SIZE: u32 = 64;
+
+/// How a source image is resized to a tile with a different aspect ratio.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum TileFit {
+    /// Scale each axis separately, distorting the image.
+    #[default]
+    Stretch,
+    /// Crop the middle of the image to the aspect ratio of the tile.
+    CenterCrop,
+    /// Crop the part of the image with the most edges, which usually holds
+    /// the subject, to the aspect ratio of the tile.
+    SmartCrop,
+    /// Scale the whole image to fit inside the tile, with black bars on the
+    /// remaining sides.
+    Letterbox,
+}
+
+impl ValueEnum for TileFit {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            TileFit::Stretch,
+            TileFit::CenterCrop,
+            TileFit::SmartCrop,
+            TileFit::Letterbox,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            TileFit::Stretch => PossibleValue::new("stretch")
+                .help("Scale the width and height separately, distorting the image."),
+            TileFit::CenterCrop => PossibleValue::new("center-crop")
+                .help("Keep the aspect ratio and crop the middle of the image."),
+            TileFit::SmartCrop => PossibleValue::new("smart-crop")
+                .help("Keep the aspect ratio and crop the part of the image with the most edges."),
+            TileFit::Letterbox => PossibleValue::new("letterbox")
+                .help("Keep the aspect ratio and fit the whole image, with black bars."),
+        })
+    }
+}
+
+impl std::fmt::Display for TileFit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl TileFit {
+    /// Resizes `image` to exactly `width` x `height`.
+    pub(crate) fn apply(self, image: &Rgb32FImage, width: u32, height: u32) -> Rgb32FImage {
+        let (image_width, image_height) = image.dimensions();
+        match self {
+            TileFit::Stretch => resize(image, width, height, FilterType::Lanczos3),
+            TileFit::CenterCrop | TileFit::SmartCrop => {
+                let (w, h) = crop_size(image_width, image_height, width, height);
+                let (x, y) = match self {
+                    TileFit::SmartCrop => salient_window(image, w, h),
+                    _ => ((image_width - w) / 2, (image_height - h) / 2),
+                };
+                resize(
+                    &*crop_imm(image, x, y, w, h),
+                    width,
+                    height,
+                    FilterType::Lanczos3,
+                )
+            }
+            TileFit::Letterbox => {
+                let scale = (f64::from(width) / f64::from(image_width))
+                    .min(f64::from(height) / f64::from(image_height));
+                let w = ((f64::from(image_width) * scale).round() as u32).clamp(1, width);
+                let h = ((f64::from(image_height) * scale).round() as u32).clamp(1, height);
+                let scaled = resize(image, w, h, FilterType::Lanczos3);
+                let mut tile = Rgb32FImage::from_pixel(width, height, Rgb([0.0; 3]));
+                let (x, y) = ((width - w) / 2, (height - h) / 2);
+                overlay(&mut tile, &scaled, i64::from(x), i64::from(y));
+                tile
+            }
+        }
+    }
+}
+
+/// The largest size with the aspect ratio of a `width` x `height` tile that
+/// fits in an `image_width` x `image_height` image.
+pub(crate) fn crop_size(
+    image_width: u32,
+    image_height: u32,
+    width: u32,
+    height: u32,
+) -> (u32, u32) {
+    let (iw, ih) = (u64::from(image_width), u64::from(image_height));
+    let (w, h) = (u64::from(width), u64::from(height));
+    if iw * h > ih * w {
+        let w = ((ih * w + h / 2) / h).clamp(1, iw);
+        (w as u32, image_height)
+    } else {
+        let h = ((iw * h + w / 2) / w).clamp(1, ih);
+        (image_width, h as u32)
+    }
+}
+
+/// Top-left corner of the `width` x `height` window of `image` holding the
+/// most edge energy, measured on a thumbnail. The window spans the image
+/// along one axis, so it only slides along the other. Windows that hold the
+/// same energy, give or take rounding, prefer the middle.
+fn salient_window(image: &Rgb32FImage, width: u32, height: u32) -> (u32, u32) {
+    let (image_width, image_height) = image.dimensions();
+    let horizontal = width < image_width;
+    let (long, window) = if horizontal {
+        (image_width, width)
+    } else if height < image_height {
+        (image_height, height)
+    } else {
+        return (0, 0);
+    };
+    let scale = f64::from(SALIENCY_SIZE) / f64::from(image_width.max(image_height));
+    let scale = scale.min(1.0);
+    let small_width = ((f64::from(image_width) * scale).round() as u32).max(1);
+    let small_height = ((f64::from(image_height) * scale).round() as u32).max(1);
+    let small = resize(image, small_width, small_height, FilterType::Triangle);
+    let shape = Shape::new(&small);
+
+    // Edge energy of every column or row of the thumbnail, in running sums.
+    let len = if horizontal {
+        small_width
+    } else {
+        small_height
+    } as usize;
+    let mut sums = vec![0.0; len + 1];
+    for y in 0..small_height as usize {
+        for x in 0..small_width as usize {
+            let (gx, gy) = shape.gradient(x, y);
+            sums[if horizontal { x } else { y } + 1] += gx.hypot(gy);
+        }
+    }
+    for i in 1..=len {
+        sums[i] += sums[i - 1];
+    }
+    let n = ((f64::from(window) * len as f64 / f64::from(long)).round() as usize).clamp(1, len);
+    let energy = |s: usize| sums[s + n] - sums[s];
+    let best = (0..=len - n).map(energy).fold(0.0, f32::max);
+    let tolerance = best * 1e-3 + 1e-4;
+    let middle = (len - n) as f64 / 2.0;
+    let start = (0..=len - n)
+        .filter(|&s| energy(s) >= best - tolerance)
+        .min_by(|&a, &b| {
+            (a as f64 - middle)
+                .abs()
+                .total_cmp(&(b as f64 - middle).abs())
+        })
+        .expect("at least one window");
+    // Windows in the middle of the thumbnail stay exactly in the middle.
+    let offset = if (start as f64 - middle).abs() <= 0.5 {
+        (long - window) / 2
+    } else {
+        (start as f64 / (len - n) as f64 * f64::from(long - window)).round() as u32
+    };
+    if horizontal {
+        (offset, 0)
+    } else {
+        (0, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crop_size_keeps_the_aspect_ratio_of_the_tile() {
+        #[rustfmt::skip]
+        let cases = [
+            // image, tile, crop
+            ((200, 100), (10, 10), (100, 100)),
+            ((100, 200), (10, 10), (100, 100)),
+            ((300, 100), (16, 9), (178, 100)),
+            ((100, 300), (16, 9), (100, 56)),
+            ((160, 90), (16, 9), (160, 90)),
+            ((1000, 1), (1, 1), (1, 1)),
+            ((5, 5), (1000, 1), (5, 1)),
+        ];
+        for ((iw, ih), (w, h), expected) in cases {
+            assert_eq!(crop_size(iw, ih, w, h), expected, "{iw}x{ih} to {w}x{h}");
+        }
+    }
+
+    #[test]
+    fn center_crop_keeps_the_middle() {
+        // Red, green and blue thirds.
+        let image = Rgb32FImage::from_fn(300, 100, |x, _| {
+            let mut rgb = [0.0; 3];
+            rgb[x as usize / 100] = 1.0;
+            Rgb(rgb)
+        });
+        let tile = TileFit::CenterCrop.apply(&image, 10, 10);
+        assert_eq!(tile.dimensions(), (10, 10));
+        for Rgb([r, g, b]) in tile.pixels() {
+            assert!(*r < 0.05 && *g > 0.95 && *b < 0.05);
+        }
+    }
+
+    /// A flat image with a checkerboard over `x..x + size`, `y..y + size`.
+    fn patch(width: u32, height: u32, (x, y): (u32, u32), size: u32) -> Rgb32FImage {
+        Rgb32FImage::from_fn(width, height, |px, py| {
+            let inside = (x..x + size).contains(&px) && (y..y + size).contains(&py);
+            let v = if inside {
+                ((px / 4 + py / 4) % 2) as f32
+            } else {
+                0.5
+            };
+            Rgb([v; 3])
+        })
+    }
+
+    #[test]
+    fn salient_window_finds_the_detailed_patch() {
+        let image = patch(300, 100, (210, 20), 60);
+        let (x, y) = salient_window(&image, 100, 100);
+        assert_eq!(y, 0);
+        assert!((170..=210).contains(&x), "{x}");
+
+        let image = patch(80, 320, (10, 30), 50);
+        let (x, y) = salient_window(&image, 80, 80);
+        assert_eq!(x, 0);
+        assert!((0..=30).contains(&y), "{y}");
+    }
+
+    #[test]
+    fn salient_window_of_a_flat_image_is_centred() {
+        let image = Rgb32FImage::from_pixel(300, 100, Rgb([0.5; 3]));
+        assert_eq!(salient_window(&image, 100, 100), (100, 0));
+        assert_eq!(salient_window(&image, 300, 100), (0, 0));
+    }
+}